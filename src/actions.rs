@@ -1,4 +1,4 @@
-use crate::{cmd, conf, tui};
+use crate::{cmd, conf, fetch, journal, markers, remote, secret, status, tui, watch, worktree};
 
 fn guess_default_branch(repo: git2::Repository) -> &'static str {
     if repo.find_branch("develop", git2::BranchType::Local).is_ok() {
@@ -24,7 +24,7 @@ pub fn clone(repo_path: &str) {
 
     let (_, result) = cmd::system(
         "git",
-        &["clone", "--bare", &repo_path],
+        &["clone", "--bare", repo_path],
         Some(&format!("{}/repos/", root_dir)),
         true,
     );
@@ -32,18 +32,103 @@ pub fn clone(repo_path: &str) {
         fail!("unable to clone repository!");
     }
 
-    let repo = git2::Repository::open_bare(&destination).unwrap();
-    let default_branch = guess_default_branch(repo);
+    let default_branch = match remote::default_branch(&destination) {
+        Ok(branch) => {
+            println!("Default branch is `{}`", branch);
+            branch
+        }
+        Err(_) => {
+            let repo = git2::Repository::open_bare(&destination).unwrap();
+            let branch = guess_default_branch(repo);
+            println!(
+                "Guessed default branch is `{}`, edit ~/.g2/g2.toml if that's not correct.",
+                branch,
+            );
+            branch.to_string()
+        }
+    };
 
     let mut config = conf::get_config();
-    config.add_repo(repo_path.to_string(), default_branch.to_string());
+    config.add_repo(repo_path.to_string(), default_branch);
     conf::set_config(&config);
 
     println!("Checked out {} to {}", repo_path, destination);
-    println!(
-        "Guessed default branch is `{}`, edit ~/.g2/g2.toml if that's not correct.",
-        default_branch,
-    );
+}
+
+pub fn repo(args: &[String]) {
+    match args.first().map(|x| x.as_str()) {
+        Some("refresh") => repo_refresh(args.get(1).map(|x| x.as_str())),
+        _ => fail!("usage: g2 repo refresh [repo_name]"),
+    }
+}
+
+// Checks whether upstream changed its default branch, e.g. from `master` to `main`, and if so
+// follows it
+fn repo_refresh(repo_filter: Option<&str>) {
+    let mut config = conf::get_config();
+    if let Some(repo_name) = repo_filter {
+        if config.get_repo_config(repo_name).is_none() {
+            fail!("repo `{}` isn't cloned!", repo_name);
+        }
+    }
+
+    let mut entry = journal::Entry::new("repo refresh");
+    let mut any_failures = false;
+    for repo_config in config.repos.iter_mut() {
+        if repo_filter.map(|r| r != repo_config.short_name()) == Some(true) {
+            continue;
+        }
+
+        let dir = fetch::repo_dir(repo_config);
+        let default_branch = match remote::default_branch(&dir) {
+            Ok(b) => b,
+            Err(_) => {
+                eprintln!(
+                    "couldn't read the default branch of {}!",
+                    repo_config.short_name()
+                );
+                any_failures = true;
+                continue;
+            }
+        };
+
+        if default_branch == repo_config.main_branch {
+            println!(
+                "{}: default branch is still `{}`",
+                repo_config.short_name(),
+                default_branch
+            );
+            continue;
+        }
+
+        entry.record_repo(repo_config);
+        let previous = std::mem::replace(&mut repo_config.main_branch, default_branch);
+        if fetch::fetch_main(repo_config, true).is_err() {
+            eprintln!("couldn't fetch {}!", repo_config.short_name());
+            repo_config.main_branch = previous;
+            any_failures = true;
+            continue;
+        }
+
+        // Keep the bare repo's HEAD pointing at the default branch, like a fresh clone would
+        if let Ok(repo) = git2::Repository::open_bare(&dir) {
+            repo.set_head(&format!("refs/heads/{}", repo_config.main_branch))
+                .ok();
+        }
+
+        println!(
+            "{}: default branch changed from `{}` to `{}`",
+            repo_config.short_name(),
+            previous,
+            repo_config.main_branch
+        );
+    }
+
+    conf::set_config(&config);
+
+    if any_failures {
+        fail!();
+    }
 }
 
 pub fn get_tmux_name() -> Option<String> {
@@ -70,10 +155,7 @@ pub fn branch_existing(branch_name: &str, with_output: bool) {
     if std::path::Path::new(&destination).exists() {
         // The branch already exists, just go there
         if with_output {
-            println!(
-                "go to {}",
-                format!("{}/branches/{}/", root_dir, branch_name)
-            );
+            println!("go to {}/branches/{}/", root_dir, branch_name);
         }
         set_tmux_name(branch_name);
         cmd::teleport(&destination);
@@ -99,19 +181,7 @@ pub fn branch_new(repo_name: &str, branch_name: &str) {
 
     let repo = git2::Repository::open_bare(format!("{}/repos/{}", root_dir, repo_name)).unwrap();
 
-    // Fetch origin. Can't do this with libgit2 because it requires authentication
-    let (_, result) = cmd::system(
-        "git",
-        &[
-            "fetch",
-            "-q",
-            "origin",
-            &format!("{}:{}", &repo_config.main_branch, &repo_config.main_branch),
-        ],
-        Some(&format!("{}/repos/{}", root_dir, repo_name)),
-        true,
-    );
-    if result.is_err() {
+    if fetch::fetch_main(repo_config, false).is_err() {
         fail!("couldn't fetch origin!");
     }
 
@@ -130,6 +200,9 @@ pub fn branch_new(repo_name: &str, branch_name: &str) {
         fail!("branch `{}` already exists!", branch_name);
     }
 
+    let branch_config = config.get_branch_config(branch_name).unwrap();
+    journal::Entry::new("new").record_created(branch_config);
+
     let branch_ref = repo
         .branch(&full_branch_name, &commit, false)
         .unwrap()
@@ -196,7 +269,7 @@ pub fn branch(args: &[String]) {
                 Ok(x) => x,
                 Err(_) => fail!("you don't have any branches!"),
             };
-            branch_existing(&options[chosen], true);
+            branch_existing(options[chosen], true);
         }
         1 => {
             branch_existing(&args[0], true);
@@ -244,166 +317,1872 @@ pub fn unwrap_or_fail(input: Result<String, String>) -> String {
 }
 
 pub fn merge_base(branch1: &str, branch2: &str) -> String {
-    let (out, result) = cmd::system("git", &["merge-base", branch1, branch2], None, false);
+    match status::merge_base(&open_current_repo(), branch1, branch2) {
+        Ok(oid) => oid.to_string(),
+        Err(_) => fail!("failed to read merge base!"),
+    }
+}
+
+// Shows the changes on the branch since it forked from main. Paths may be globs, which git
+// matches itself.
+pub fn diff(args: &[String]) {
+    let mut since_upload = false;
+    let mut uploads = None;
+    let mut stat = false;
+    let mut cached = false;
+    let mut worktree = false;
+    let mut paths = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--stat" => stat = true,
+            "--cached" => cached = true,
+            "--worktree" => worktree = true,
+            "--since-upload" => since_upload = true,
+            "--uploads" => match iter.next() {
+                Some(range) => uploads = Some(range.as_str()),
+                None => fail!("`--uploads` needs a range of uploads, like `2..3`"),
+            },
+            "--" => paths.extend(iter.by_ref().map(|p| p.as_str())),
+            _ if arg.starts_with("--") => fail!("unknown argument `{}`", arg),
+            _ => paths.push(arg.as_str()),
+        }
+    }
+    if cached && worktree {
+        fail!("`--cached` and `--worktree` can't be used together");
+    }
+    if worktree && (since_upload || uploads.is_some()) {
+        fail!("`--worktree` only shows unstaged changes, it can't be compared to an upload");
+    }
+
+    let (repo_config, branch_config) = conf::get_current_dir_configs();
+    let repo = open_current_repo();
+    let main = &repo_config.main_branch;
+
+    let (from, to) = if let Some(range) = uploads {
+        let recorded = upload_refs(&repo, &branch_config.branch_name);
+        let find = |n: &str| -> git2::Commit {
+            let n = match n.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => fail!("`{}` isn't an upload number", n),
+            };
+            match recorded.iter().find(|(idx, _)| *idx == n) {
+                Some((_, c)) => c.clone(),
+                None => fail!("there's no upload {}, the latest is {}", n, recorded.len()),
+            }
+        };
+        match range.split_once("..") {
+            Some((a, b)) => {
+                let (a, b) = (find(a), find(b));
+                (rebased_onto(&repo, &a, &b, main), Some(b.id().to_string()))
+            }
+            None => {
+                let head = repo.head().unwrap().peel_to_commit().unwrap();
+                (rebased_onto(&repo, &find(range), &head, main), None)
+            }
+        }
+    } else if since_upload {
+        let latest = latest_upload(&repo, &branch_config.branch_name);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        (rebased_onto(&repo, &latest, &head, main), None)
+    } else {
+        (merge_base(&branch_config.branch_name, main), None)
+    };
+
+    if cached && to.is_some() {
+        fail!("`--cached` can't be used to compare two uploads");
+    }
+
+    let config = conf::get_config();
+    let pager = format!("core.pager={}", config.diff_pager);
+    let tool = format!("diff.external={}", config.diff_tool);
+    let mut git_args = Vec::new();
+    if !config.diff_pager.is_empty() {
+        git_args.extend(&["-c", pager.as_str()]);
+    }
+    if !config.diff_tool.is_empty() {
+        git_args.extend(&["-c", tool.as_str()]);
+    }
+    git_args.push("diff");
+    if !config.diff_tool.is_empty() {
+        git_args.push("--ext-diff");
+    }
+    if stat {
+        git_args.push("--stat");
+    }
+    if cached {
+        git_args.push("--cached");
+    }
+    // Unstaged changes are between the index and the worktree, so there's no base
+    if !worktree {
+        git_args.push(&from);
+    }
+    if let Some(to) = &to {
+        git_args.push(to);
+    }
+    if !paths.is_empty() {
+        git_args.push("--");
+        git_args.extend(paths);
+    }
+    let (_, result) = cmd::system("git", &git_args, None, true);
     if result.is_err() {
-        fail!("failed to read merge base!");
+        fail!("unable to get diff!");
+    }
+}
+
+// Replays the changes made on `commit` onto the version of main that `target` is based on, so
+// that comparing it with `target` doesn't show changes that came in by syncing with main.
+// Returns the id of the resulting tree, or of `commit` itself if the replay conflicts.
+fn rebased_onto(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    target: &git2::Commit,
+    main: &str,
+) -> String {
+    let main = match repo.revparse_single(main).and_then(|o| o.peel_to_commit()) {
+        Ok(m) => m.id(),
+        Err(_) => fail!("couldn't find `{}`!", main),
+    };
+    let (base, onto) = match (
+        repo.merge_base(commit.id(), main),
+        repo.merge_base(target.id(), main),
+    ) {
+        (Ok(base), Ok(onto)) => (base, onto),
+        _ => fail!("failed to read merge base!"),
+    };
+    if base == onto {
+        return commit.id().to_string();
+    }
+
+    let tree = |oid| repo.find_commit(oid).and_then(|c| c.tree());
+    let merged = match (tree(base), tree(onto)) {
+        (Ok(base), Ok(onto)) => repo.merge_trees(&base, &onto, &commit.tree().unwrap(), None),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    match merged {
+        Ok(mut index) if !index.has_conflicts() => match index.write_tree_to(repo) {
+            Ok(oid) => oid.to_string(),
+            Err(_) => commit.id().to_string(),
+        },
+        _ => {
+            eprintln!("the changes from main since then conflict, so they're included below");
+            commit.id().to_string()
+        }
+    }
+}
+
+pub fn get_files() -> Vec<String> {
+    let mut output = Vec::new();
+    for change in file_changes(false, false) {
+        output.push(change.path);
+    }
+    output.sort();
+    output
+}
+
+// The changes on the current branch, relative to where it forked from main. Renames are only
+// worth finding when the changes are going to be shown.
+fn file_changes(count_lines: bool, find_renames: bool) -> Vec<status::FileChange> {
+    let (repo_config, branch_config) = conf::get_current_dir_configs();
+    let repo = open_current_repo();
+    let base = match status::merge_base(&repo, &branch_config.branch_name, &repo_config.main_branch)
+    {
+        Ok(b) => b,
+        Err(_) => fail!("failed to read merge base!"),
+    };
+    file_changes_since(base, count_lines, find_renames)
+}
+
+// The changes in the worktree relative to the commit `base`
+fn file_changes_since(
+    base: git2::Oid,
+    count_lines: bool,
+    find_renames: bool,
+) -> Vec<status::FileChange> {
+    let (_, branch_config) = conf::get_current_dir_configs();
+    let repo = open_current_repo();
+    let ignored = &branch_config.ignored_files;
+    match status::file_changes(&repo, base, ignored, count_lines, find_renames) {
+        Ok(f) => f,
+        Err(e) => fail!("unable to get diff! error: {}", e.message()),
+    }
+}
+
+// Lists the files changed on the branch, relative to the root of the worktree unless
+// `--relative` is passed
+pub fn files(args: &[String]) {
+    use status::ChangeKind;
+
+    let mut json = false;
+    let mut nul = false;
+    let mut relative = false;
+    let mut kinds = Vec::new();
+    let mut globs = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-0" => nul = true,
+            "--relative" => relative = true,
+            "--added" => kinds.push(ChangeKind::Added),
+            "--modified" => kinds.push(ChangeKind::Modified),
+            "--deleted" => kinds.push(ChangeKind::Deleted),
+            "--untracked" => kinds.push(ChangeKind::Untracked),
+            "--glob" => match iter.next().map(|g| glob::Pattern::new(g)) {
+                Some(Ok(pattern)) => globs.push(pattern),
+                Some(Err(e)) => fail!("invalid glob: {}", e),
+                None => fail!("`--glob` needs a pattern"),
+            },
+            _ => fail!("unknown argument `{}`", arg),
+        }
+    }
+
+    // Renames count as deleting the old path and adding the new one
+    let wanted = |kind: ChangeKind, path: &str| {
+        let kind = match kind {
+            ChangeKind::Copied | ChangeKind::Renamed => ChangeKind::Added,
+            ChangeKind::ModeChanged | ChangeKind::TypeChange => ChangeKind::Modified,
+            k => k,
+        };
+        (kinds.is_empty() || kinds.contains(&kind))
+            && (globs.is_empty() || globs.iter().any(|g| g.matches(path)))
+    };
+
+    let deleted_from = |c: &status::FileChange| match (c.kind, &c.old_path) {
+        (ChangeKind::Renamed, Some(old)) => wanted(ChangeKind::Deleted, old),
+        _ => false,
+    };
+
+    let prefix = if relative {
+        current_prefix()
+    } else {
+        String::new()
+    };
+    let mut changes = file_changes(json, true);
+    changes.retain(|c| wanted(c.kind, &c.path) || deleted_from(c));
+
+    if json {
+        for change in &mut changes {
+            change.path = relative_path(&change.path, &prefix);
+            change.old_path = change.old_path.as_ref().map(|p| relative_path(p, &prefix));
+        }
+        print_json(&changes);
+        return;
+    }
+
+    let mut output = Vec::new();
+    for change in &changes {
+        if deleted_from(change) {
+            output.push(relative_path(change.old_path.as_ref().unwrap(), &prefix));
+        }
+        if wanted(change.kind, &change.path) {
+            output.push(relative_path(&change.path, &prefix));
+        }
+    }
+    output.sort();
+
+    for path in output {
+        if nul {
+            print!("{}\0", path);
+        } else {
+            println!("{}", path);
+        }
+    }
+}
+
+// The root of the current worktree. Paths from `get_files` and `file_changes` are relative to
+// it, not to the current directory.
+fn worktree_root() -> std::path::PathBuf {
+    let repo = open_current_repo();
+    match repo.workdir().and_then(|w| w.canonicalize().ok()) {
+        Some(w) => w,
+        None => fail!("couldn't find the root of the worktree!"),
+    }
+}
+
+// The current directory, relative to the root of the worktree, e.g. `src/` or empty at the root
+fn current_prefix() -> String {
+    let workdir = worktree_root();
+    let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+    match cwd.strip_prefix(&workdir) {
+        Ok(p) if p.as_os_str().is_empty() => String::new(),
+        Ok(p) => format!("{}/", p.to_string_lossy()),
+        Err(_) => String::new(),
+    }
+}
+
+// Turns a path given on the command line, relative to the current directory, into one relative
+// to the root of the worktree
+fn repo_path(arg: &str) -> String {
+    normalize_path(&format!("{}{}", current_prefix(), arg))
+}
+
+// Resolves `.` and `..` components, without looking at the filesystem
+fn normalize_path(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                if parts.pop().is_none() {
+                    fail!("`{}` is outside of the worktree!", path);
+                }
+            }
+            p => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+// Turns a path relative to the root of the worktree into one relative to `prefix`, a
+// subdirectory as returned by `current_prefix`
+fn relative_path(path: &str, prefix: &str) -> String {
+    let mut prefix_parts: Vec<_> = prefix.split('/').filter(|p| !p.is_empty()).collect();
+    let mut path_parts: Vec<_> = path.split('/').collect();
+    while !prefix_parts.is_empty() && path_parts.len() > 1 && prefix_parts[0] == path_parts[0] {
+        prefix_parts.remove(0);
+        path_parts.remove(0);
+    }
+
+    let mut output: Vec<&str> = prefix_parts.iter().map(|_| "..").collect();
+    output.extend(path_parts);
+    output.join("/")
+}
+
+// Snapshots the worktree so that it can be restored later, returning the snapshot commit
+fn snapshot(repo_config: &conf::RepoConfig, branch_config: &conf::BranchConfig) -> String {
+    let excluded = prepare_snapshot(repo_config, branch_config);
+
+    match repo_config.snapshot_mode {
+        conf::SnapshotMode::Commit => {
+            commit_all(&branch_config.branch_name, &excluded);
+            let repo = open_current_repo();
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            head.id().to_string()
+        }
+        conf::SnapshotMode::Ref => snapshot_to_ref(&branch_config.branch_name, &excluded),
+    }
+}
+
+// Checks that the worktree is fit to be snapshotted, returning the untracked files which
+// should be left out
+fn prepare_snapshot(
+    repo_config: &conf::RepoConfig,
+    branch_config: &conf::BranchConfig,
+) -> Vec<String> {
+    let excluded = confirm_untracked_files(repo_config, branch_config);
+    check_conflict_markers(repo_config, "g2 sync");
+    check_secrets(repo_config, &excluded);
+    excluded
+}
+
+// Stages everything in the worktree, apart from the `excluded` untracked files
+fn stage_all(excluded: &[String]) {
+    let root = worktree_root();
+    let mut c = std::process::Command::new("git");
+    c.arg("add").arg(".").current_dir(&root);
+    unwrap_or_fail(get_stdout(c));
+
+    if excluded.is_empty() {
+        return;
+    }
+    let mut args = vec!["reset", "-q", "--"];
+    args.extend(excluded.iter().map(|f| f.as_str()));
+    let (out, res) = cmd::system("git", &args, root.to_str(), false);
+    if res.is_err() {
+        fail!("couldn't unstage excluded files: {}", out.trim());
+    }
+}
+
+fn commit_all(msg: &str, excluded: &[String]) {
+    stage_all(excluded);
+
+    let mut c = std::process::Command::new("git");
+    c.arg("commit").arg("-n").arg("-m").arg(msg);
+    get_status(c);
+}
+
+// Untracked files in the worktree, relative to its root
+fn untracked_files(repo: &git2::Repository) -> Vec<String> {
+    let mut opts = git2::StatusOptions::new();
+    opts.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .exclude_submodules(true);
+    let statuses = match repo.statuses(Some(&mut opts)) {
+        Ok(s) => s,
+        Err(e) => fail!("couldn't read the status of the worktree: {}", e.message()),
+    };
+    statuses
+        .iter()
+        .filter(|s| s.status().is_wt_new())
+        .filter_map(|s| s.path().map(|p| p.to_string()))
+        .collect()
+}
+
+// The untracked files which the user chose to leave out of snapshots on this branch
+fn ignored_files(branch_config: &conf::BranchConfig) -> Vec<String> {
+    let untracked = untracked_files(&open_current_repo());
+    branch_config
+        .ignored_files
+        .iter()
+        .filter(|f| untracked.contains(f))
+        .cloned()
+        .collect()
+}
+
+// Asks which untracked files that haven't been seen before should go into the snapshot,
+// remembering the answers for the branch. When there's no terminal to ask in, the repo's
+// `untracked_policy` decides. Returns the files to leave out.
+fn confirm_untracked_files(
+    repo_config: &conf::RepoConfig,
+    branch_config: &conf::BranchConfig,
+) -> Vec<String> {
+    use std::io::IsTerminal;
+
+    let untracked = untracked_files(&open_current_repo());
+
+    // Files which have since been committed or deleted don't need remembering
+    let mut updated = branch_config.clone();
+    updated.ignored_files.retain(|f| untracked.contains(f));
+    updated.included_files.retain(|f| untracked.contains(f));
+
+    let new_files: Vec<String> = untracked
+        .into_iter()
+        .filter(|f| !updated.ignored_files.contains(f) && !updated.included_files.contains(f))
+        .collect();
+
+    let mut skipped = Vec::new();
+    if new_files.is_empty() {
+        // Nothing to ask about
+    } else if std::io::stdin().is_terminal() {
+        let chosen = tui::multi_select(
+            "found new untracked files, choose which to include (space to toggle):",
+            &new_files,
+        );
+        for (idx, file) in new_files.into_iter().enumerate() {
+            if chosen.contains(&idx) {
+                updated.included_files.push(file);
+            } else {
+                updated.ignored_files.push(file);
+            }
+        }
+    } else {
+        match repo_config.untracked_policy {
+            conf::UntrackedPolicy::Include => updated.included_files.extend(new_files),
+            conf::UntrackedPolicy::Skip => skipped = new_files,
+            conf::UntrackedPolicy::Fail => {
+                println!("found new untracked files:\n");
+                for file in new_files {
+                    println!("  {}", file);
+                }
+                fail!("\nrun g2 in a terminal to choose which of them to include");
+            }
+        }
+    }
+
+    if updated.ignored_files != branch_config.ignored_files
+        || updated.included_files != branch_config.included_files
+    {
+        let mut config = conf::get_config();
+        config.set_branch_config(updated.clone());
+        conf::set_config(&config);
+    }
+
+    updated.ignored_files.extend(skipped);
+    updated.ignored_files
+}
+
+// Returns the path to a hook if it's installed. git skips hooks which aren't executable, so
+// those don't count.
+fn find_hook(workdir: &str, name: &str) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Resolves to the shared hooks directory, or `core.hooksPath` if it's set
+    let (out, res) = cmd::system(
+        "git",
+        &["rev-parse", "--git-path", "hooks"],
+        Some(workdir),
+        false,
+    );
+    if res.is_err() {
+        return None;
+    }
+
+    let path = std::path::Path::new(workdir).join(out.trim()).join(name);
+    let metadata = std::fs::metadata(&path).ok()?;
+    if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+        Some(path.to_string_lossy().to_string())
+    } else {
+        None
+    }
+}
+
+// Runs the pre-commit and commit-msg hooks the way `git commit` would, against every change on
+// the branch since `base`, and quits if either of them fails
+fn run_commit_hooks(msg: &str, base: &str, excluded: &[String]) {
+    let (out, res) = cmd::system("git", &["rev-parse", "--show-toplevel"], None, false);
+    if res.is_err() {
+        fail!("couldn't find the root of the worktree: {}", out.trim());
+    }
+    let workdir = out.trim();
+
+    stage_all(excluded);
+    let (tree, res) = cmd::system("git", &["write-tree"], Some(workdir), false);
+    if res.is_err() {
+        fail!("couldn't prepare to run hooks: {}", tree.trim());
+    }
+
+    // Hooks look at what's staged relative to HEAD, but earlier snapshots are already committed.
+    // So they run in a throwaway checkout whose HEAD is `base` and whose index holds the whole
+    // branch, leaving the real worktree alone even if g2 is interrupted. Edits the hooks make
+    // there, e.g. by formatters, are thrown away.
+    let checkout = std::env::temp_dir().join(format!("g2-hooks-{}", std::process::id()));
+    let checkout = checkout.to_str().unwrap();
+    let (out, res) = cmd::system(
+        "git",
+        &[
+            "worktree",
+            "add",
+            "-q",
+            "--detach",
+            "--no-checkout",
+            checkout,
+            base,
+        ],
+        Some(workdir),
+        false,
+    );
+    if res.is_err() {
+        fail!("couldn't prepare to run hooks: {}", out.trim());
+    }
+
+    let result = prepare_hook_checkout(workdir, checkout, tree.trim())
+        .and_then(|_| run_hooks(checkout, msg));
+
+    let (_, res) = cmd::system(
+        "git",
+        &["worktree", "remove", "--force", checkout],
+        Some(workdir),
+        false,
+    );
+    if res.is_err() {
+        eprintln!(
+            "couldn't remove {}, remove it with `git worktree remove`",
+            checkout
+        );
+    }
+    if let Err(e) = result {
+        fail!("{}", e);
+    }
+}
+
+// Fills in the hook checkout with `tree`, staged on top of the base. Ignored directories at the
+// top of the worktree, like `node_modules`, are linked in so that hooks can use them.
+fn prepare_hook_checkout(workdir: &str, checkout: &str, tree: &str) -> Result<(), String> {
+    let (out, res) = cmd::system("git", &["read-tree", tree], Some(checkout), false);
+    if res.is_err() {
+        return Err(format!("couldn't prepare to run hooks: {}", out.trim()));
+    }
+    let (out, res) = cmd::system(
+        "git",
+        &["checkout-index", "-a", "-f"],
+        Some(checkout),
+        false,
+    );
+    if res.is_err() {
+        return Err(format!("couldn't prepare to run hooks: {}", out.trim()));
+    }
+
+    let (out, _) = cmd::system(
+        "git",
+        &[
+            "ls-files",
+            "--others",
+            "--ignored",
+            "--exclude-standard",
+            "--directory",
+        ],
+        Some(workdir),
+        false,
+    );
+    for dir in out.lines().filter_map(|l| l.trim().strip_suffix('/')) {
+        if dir.contains('/') {
+            continue;
+        }
+        let link = std::path::Path::new(checkout).join(dir);
+        if !link.exists() {
+            std::os::unix::fs::symlink(std::path::Path::new(workdir).join(dir), link).ok();
+        }
+    }
+    Ok(())
+}
+
+fn run_hooks(workdir: &str, msg: &str) -> Result<(), String> {
+    if let Some(hook) = find_hook(workdir, "pre-commit") {
+        println!("running pre-commit hook...");
+        let (_, res) = cmd::system(&hook, &[], Some(workdir), true);
+        if res.is_err() {
+            return Err(String::from("the pre-commit hook failed, not uploading"));
+        }
+    }
+
+    if let Some(hook) = find_hook(workdir, "commit-msg") {
+        println!("running commit-msg hook...");
+        let filename = format!("/tmp/g2.{}.commit-msg", msg.replace('/', "-"));
+        std::fs::write(&filename, format!("{}\n", msg)).unwrap();
+        let (_, res) = cmd::system(&hook, &[&filename], Some(workdir), true);
+        std::fs::remove_file(&filename).ok();
+        if res.is_err() {
+            return Err(String::from("the commit-msg hook failed, not uploading"));
+        }
+    }
+    Ok(())
+}
+
+// How many snapshots to keep under private refs for each branch. `g2 watch` makes one after every
+// quiet period, so the oldest are dropped.
+const MAX_SNAPSHOT_REFS: usize = 100;
+
+fn snapshot_ref_prefix(branch_name: &str) -> String {
+    format!("refs/g2/snapshots/{}/", branch_name)
+}
+
+// Returns the snapshots stored under private refs for the branch, oldest first
+fn snapshot_refs<'a>(
+    repo: &'a git2::Repository,
+    branch_name: &str,
+) -> Vec<(usize, git2::Commit<'a>)> {
+    numbered_refs(repo, &snapshot_ref_prefix(branch_name))
+}
+
+fn upload_ref_prefix(branch_name: &str) -> String {
+    format!("refs/g2/uploads/{}/", branch_name)
+}
+
+// Returns the commits pushed by each upload of the branch, oldest first
+fn upload_refs<'a>(
+    repo: &'a git2::Repository,
+    branch_name: &str,
+) -> Vec<(usize, git2::Commit<'a>)> {
+    numbered_refs(repo, &upload_ref_prefix(branch_name))
+}
+
+// The commit pushed by the most recent upload of the branch
+fn latest_upload<'a>(repo: &'a git2::Repository, branch_name: &str) -> git2::Commit<'a> {
+    match upload_refs(repo, branch_name).pop() {
+        Some((_, c)) => c,
+        // Uploaded before uploads were recorded
+        None => match repo
+            .find_reference(&remote::tracking_ref(branch_name))
+            .and_then(|r| r.peel_to_commit())
+        {
+            Ok(c) => c,
+            Err(_) => fail!("this branch hasn't been uploaded yet!"),
+        },
+    }
+}
+
+// Remembers what was pushed, so later uploads can be compared against it. Uploading the same
+// commit twice only records it once.
+fn record_upload(branch_name: &str) {
+    let repo = open_current_repo();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    let existing = upload_refs(&repo, branch_name);
+    if let Some((_, latest)) = existing.last() {
+        if latest.id() == head.id() {
+            return;
+        }
+    }
+
+    let n = existing.last().map(|(n, _)| n + 1).unwrap_or(1);
+    let result = repo.reference(
+        &format!("{}{}", upload_ref_prefix(branch_name), n),
+        head.id(),
+        false,
+        "g2: upload",
+    );
+    if let Err(e) = result {
+        eprintln!("couldn't record the upload: {}", e.message());
+    }
+}
+
+// Refs named `<prefix><n>`, ordered by n
+fn numbered_refs<'a>(repo: &'a git2::Repository, prefix: &str) -> Vec<(usize, git2::Commit<'a>)> {
+    let mut output: Vec<_> = repo
+        .references_glob(&format!("{}*", prefix))
+        .unwrap()
+        .filter_map(|r| r.ok())
+        .filter_map(|r| {
+            let n = r.name()?[prefix.len()..].parse::<usize>().ok()?;
+            Some((n, r.peel_to_commit().ok()?))
+        })
+        .collect();
+    output.sort_by_key(|(n, _)| *n);
+    output
+}
+
+// Records the worktree in a commit which only a private ref points to, so the branch history
+// isn't touched
+fn snapshot_to_ref(branch_name: &str, excluded: &[String]) -> String {
+    let repo = open_current_repo();
+    let tree = worktree::tree_excluding(&repo, excluded).unwrap();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+
+    let existing = snapshot_refs(&repo, branch_name);
+    if let Some((_, latest)) = existing.last() {
+        if latest.tree_id() == tree.id() {
+            return latest.id().to_string();
+        }
+    }
+    if head.tree_id() == tree.id() {
+        return head.id().to_string();
+    }
+
+    let n = existing.last().map(|(n, _)| n + 1).unwrap_or(1);
+    let oid = match worktree::commit_tree(&repo, &tree, branch_name) {
+        Ok(oid) => oid,
+        Err(e) => fail!("couldn't snapshot the worktree: {}", e.message()),
+    };
+    if let Err(e) = repo.reference(
+        &format!("{}{}", snapshot_ref_prefix(branch_name), n),
+        oid,
+        false,
+        "g2: snapshot",
+    ) {
+        fail!("couldn't save the snapshot: {}", e.message());
+    }
+    prune_snapshot_refs(&repo, branch_name, MAX_SNAPSHOT_REFS);
+    oid.to_string()
+}
+
+// Deletes the oldest snapshot refs of the branch, keeping the most recent `keep`
+fn prune_snapshot_refs(repo: &git2::Repository, branch_name: &str, keep: usize) {
+    let prefix = snapshot_ref_prefix(branch_name);
+    let existing = snapshot_refs(repo, branch_name);
+    let stale = existing.len().saturating_sub(keep);
+    for (n, _) in &existing[..stale] {
+        if let Ok(mut r) = repo.find_reference(&format!("{}{}", prefix, n)) {
+            r.delete().ok();
+        }
+    }
+}
+
+// Finds SCM change markers in the files to add. Files can opt out via the
+// `allow_conflict_markers` config or the `g2-allow-markers` git attribute.
+fn find_conflict_markers(repo_config: &conf::RepoConfig) -> Vec<String> {
+    let root = worktree_root();
+    let mut conflicts = Vec::new();
+    for file in get_files() {
+        if repo_config.allows_conflict_markers(&file) {
+            continue;
+        }
+
+        let contents = match std::fs::read(root.join(&file)) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let found = markers::find_markers(&contents);
+        if found.is_empty() || has_marker_attribute(&root, &file) {
+            continue;
+        }
+
+        for marker in found {
+            conflicts.push(format!("{}:{}: {}", file, marker.line, marker.text));
+        }
+    }
+    conflicts
+}
+
+// Check that there are no SCM change markers in the files to add, telling the user to run
+// `retry_command` once they're resolved
+fn check_conflict_markers(repo_config: &conf::RepoConfig, retry_command: &str) {
+    let conflicts = find_conflict_markers(repo_config);
+    if !conflicts.is_empty() {
+        println!("the following files contain SCM change markers:\n");
+        for conflict in conflicts {
+            println!("  {}", conflict);
+        }
+        fail!(
+            "\nresolve the conflicts first, then run `{}` again",
+            retry_command
+        );
+    }
+}
+
+// Refuse to commit anything that looks like a credential, or is too large. Files can opt out
+// via the `allow_secrets` config.
+fn check_secrets(repo_config: &conf::RepoConfig, excluded: &[String]) {
+    let root = worktree_root();
+    let mut findings = Vec::new();
+    for file in get_files() {
+        if repo_config.allows_secrets(&file) || excluded.contains(&file) {
+            continue;
+        }
+
+        let path = root.join(&file);
+        let size = match std::fs::metadata(&path) {
+            Ok(m) if m.is_file() => m.len(),
+            _ => continue,
+        };
+        // No need to read a file that's already too big
+        let contents = if size > repo_config.max_file_size {
+            Vec::new()
+        } else {
+            std::fs::read(&path).unwrap_or_default()
+        };
+
+        for finding in secret::scan(&file, size, &contents, repo_config.max_file_size) {
+            match finding.line {
+                Some(line) => findings.push(format!("{}:{}: {}", file, line, finding.reason)),
+                None => findings.push(format!("{}: {}", file, finding.reason)),
+            }
+        }
+    }
+
+    if !findings.is_empty() {
+        println!("the following files shouldn't be committed:\n");
+        for finding in findings {
+            println!("  {}", finding);
+        }
+        fail!(
+            "\nremove them, or add them to `allow_secrets` for {} in ~/.g2/g2.toml",
+            repo_config.short_name()
+        );
+    }
+}
+
+fn has_marker_attribute(root: &std::path::Path, file: &str) -> bool {
+    let (out, res) = cmd::system(
+        "git",
+        &["check-attr", "g2-allow-markers", "--", file],
+        root.to_str(),
+        false,
+    );
+    if res.is_err() {
+        return false;
+    }
+
+    // Output looks like `<file>: g2-allow-markers: <set|unset|unspecified|value>`
+    match out.trim().rsplit(": ").next() {
+        Some("unset") | Some("unspecified") | Some("false") | None => false,
+        Some(_) => true,
+    }
+}
+
+// Returns the kind of sync that was interrupted by conflicts in the current worktree, if any
+fn pending_sync(workdir: &str) -> Result<Option<conf::SyncStrategy>, String> {
+    let repo = match git2::Repository::discover(workdir) {
+        Ok(r) => r,
+        Err(_) => return Err(format!("couldn't open the repository at `{}`!", workdir)),
+    };
+
+    Ok(match repo.state() {
+        git2::RepositoryState::Merge => Some(conf::SyncStrategy::Merge),
+        git2::RepositoryState::Rebase
+        | git2::RepositoryState::RebaseInteractive
+        | git2::RepositoryState::RebaseMerge
+        | git2::RepositoryState::ApplyMailboxOrRebase => Some(conf::SyncStrategy::Rebase),
+        _ => None,
+    })
+}
+
+// The sync in progress in the current worktree
+fn current_pending_sync() -> Option<conf::SyncStrategy> {
+    match pending_sync(".") {
+        Ok(s) => s,
+        Err(e) => fail!("{}", e),
+    }
+}
+
+pub fn sync(args: &[String]) {
+    let mut strategy = None;
+    let mut all = false;
+    let mut dry_run = false;
+    let mut force_fetch = false;
+    let mut resume = None;
+    let mut repo_filter = None;
+    for arg in args {
+        match arg.as_str() {
+            "--fetch" | "--no-cache" => force_fetch = true,
+            "--merge" => strategy = Some(conf::SyncStrategy::Merge),
+            "--rebase" => strategy = Some(conf::SyncStrategy::Rebase),
+            "--all" => all = true,
+            "--dry-run" => dry_run = true,
+            "--continue" | "--abort" => resume = Some(arg.as_str()),
+            x if all && !x.starts_with('-') && repo_filter.is_none() => repo_filter = Some(x),
+            _ => fail!("unknown argument `{}` to `sync`!", arg),
+        }
+    }
+
+    if all {
+        if dry_run {
+            fail!("`--dry-run` can't be combined with `--all`!");
+        }
+        return sync_all(repo_filter, strategy, force_fetch);
+    }
+
+    let (repo_config, branch_config) = conf::get_current_dir_configs();
+    match resume {
+        Some("--continue") => return sync_continue(&repo_config, &branch_config),
+        Some(_) => return sync_abort(&branch_config),
+        None => (),
+    }
+
+    if current_pending_sync().is_some() {
+        fail!("a sync is already in progress! finish it with `g2 sync --continue` or roll it back with `g2 sync --abort`");
+    }
+
+    if fetch::fetch_main(&repo_config, force_fetch).is_err() {
+        fail!("couldn't fetch origin!");
+    }
+
+    let strategy = strategy.unwrap_or(repo_config.sync_strategy);
+    if dry_run {
+        return sync_dry_run(&repo_config, strategy);
+    }
+
+    journal::Entry::new("sync").record(&branch_config);
+
+    // Snapshot so we can merge incoming changes
+    snapshot(&repo_config, &branch_config);
+
+    // Snapshots under private refs leave the changes in the worktree, so they're set aside
+    // until the sync is done
+    if repo_config.snapshot_mode == conf::SnapshotMode::Ref {
+        stash_changes(&branch_config.branch_name);
+    }
+
+    // Try to merge or rebase onto the incoming changes
+    let (_, res) = merge_main(&repo_config.main_branch, strategy, None);
+    if res.is_err() {
+        // There may have been a conflict
+        report_conflicts();
+        eprintln!("\nfix the conflicts, then run `g2 sync --continue`");
+        return;
+    }
+    restore_stash(&branch_config.branch_name);
+}
+
+// Where `stash_changes` keeps the branch's uncommitted changes during a sync. The stash list is
+// shared by every worktree of the repo, so it isn't used.
+fn stash_ref(branch_name: &str) -> String {
+    format!("refs/g2/stashes/{}", branch_name)
+}
+
+// Moves the uncommitted changes out of the worktree, untracked files included, which
+// `--autostash` would leave behind
+fn stash_changes(branch_name: &str) {
+    let name = stash_ref(branch_name);
+    let (_, res) = cmd::system("git", &["rev-parse", "-q", "--verify", &name], None, false);
+    if res.is_ok() {
+        fail!(
+            "changes from an earlier sync are still stashed in `{}`! restore them with `git stash apply {}`, then run `git update-ref -d {}`",
+            name,
+            name,
+            name
+        );
+    }
+
+    let (out, res) = cmd::system("git", &["status", "--porcelain"], None, false);
+    if res.is_err() {
+        fail!("couldn't read the status of the worktree: {}", out.trim());
+    }
+    if out.trim().is_empty() {
+        return;
+    }
+
+    let msg = format!("g2 sync {}", branch_name);
+    let args = ["stash", "push", "-q", "--include-untracked", "-m", &msg];
+    let (out, res) = cmd::system("git", &args, None, false);
+    if res.is_err() {
+        fail!("couldn't stash your changes: {}", out.trim());
+    }
+    let (stash, res) = cmd::system("git", &["rev-parse", "refs/stash"], None, false);
+    if res.is_err() {
+        fail!("couldn't find the stash: {}", stash.trim());
+    }
+    let (out, res) = cmd::system("git", &["update-ref", &name, stash.trim()], None, false);
+    if res.is_err() {
+        fail!("couldn't save the stash: {}", out.trim());
+    }
+    let _ = cmd::system("git", &["stash", "drop", "-q"], None, false);
+}
+
+// Puts back the changes set aside by `stash_changes`, if there are any
+fn restore_stash(branch_name: &str) {
+    let name = stash_ref(branch_name);
+    let (_, res) = cmd::system("git", &["rev-parse", "-q", "--verify", &name], None, false);
+    if res.is_err() {
+        return;
+    }
+
+    let (out, res) = cmd::system("git", &["stash", "apply", "-q", &name], None, false);
+    if res.is_err() {
+        eprintln!("{}", out.trim());
+        eprintln!(
+            "\ncouldn't put back all of your uncommitted changes, they're saved in `{}`",
+            name
+        );
+        eprintln!(
+            "once you've fixed the worktree, run `git update-ref -d {}`",
+            name
+        );
+        return;
+    }
+    let _ = cmd::system("git", &["update-ref", "-d", &name], None, false);
+}
+
+// The files with conflicts in the result of an in-memory merge
+fn conflicted_paths(index: &git2::Index) -> Result<Vec<String>, git2::Error> {
+    let mut output = Vec::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+            output.push(String::from_utf8_lossy(&entry.path).to_string());
+        }
+    }
+    output.sort();
+    output.dedup();
+    Ok(output)
+}
+
+// The commits between `main` and `head` which make the same change as a commit already on
+// `main`, judged by patch id the way `git cherry` does
+fn upstream_equivalents(
+    repo: &git2::Repository,
+    head: &git2::Commit,
+    main: &git2::Commit,
+) -> Result<Vec<git2::Oid>, git2::Error> {
+    let workdir = repo.workdir().and_then(|w| w.to_str());
+    let (head_id, main_id) = (head.id().to_string(), main.id().to_string());
+    let (out, res) = cmd::system("git", &["cherry", &main_id, &head_id], workdir, false);
+    if res.is_err() {
+        return Err(git2::Error::from_str(out.trim()));
+    }
+    out.lines()
+        .filter_map(|line| line.strip_prefix("- "))
+        .map(|oid| git2::Oid::from_str(oid.trim()))
+        .collect()
+}
+
+// Replays the branch's commits onto main one at a time in memory, like a rebase would, then the
+// uncommitted changes in `worktree`. Returns where the rebase would first stop, and the files
+// which would conflict there.
+fn predict_rebase(
+    repo: &git2::Repository,
+    head: &git2::Commit,
+    main: &git2::Commit,
+    worktree: &git2::Tree,
+) -> Result<Option<(String, Vec<String>)>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push(head.id())?;
+    revwalk.hide(main.id())?;
+
+    let upstream = upstream_equivalents(repo, head, main)?;
+    let mut current = main.tree()?;
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        // Like `git rebase`, merge commits and commits whose changes are already on main are
+        // dropped
+        if commit.parent_count() != 1 || upstream.contains(&commit.id()) {
+            continue;
+        }
+        let mut index =
+            repo.merge_trees(&commit.parent(0)?.tree()?, &current, &commit.tree()?, None)?;
+        let conflicts = conflicted_paths(&index)?;
+        if !conflicts.is_empty() {
+            let stop = format!(
+                "{} {}",
+                &commit.id().to_string()[..7],
+                commit.summary().unwrap_or("")
+            );
+            return Ok(Some((stop, conflicts)));
+        }
+        current = repo.find_tree(index.write_tree_to(repo)?)?;
+    }
+
+    let index = repo.merge_trees(&head.tree()?, &current, worktree, None)?;
+    let conflicts = conflicted_paths(&index)?;
+    if conflicts.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some((String::from("your uncommitted changes"), conflicts)))
+    }
+}
+
+// Predict the result of a sync by merging main into, or rebasing the branch onto main, in memory
+fn sync_dry_run(repo_config: &conf::RepoConfig, strategy: conf::SyncStrategy) {
+    let repo = match git2::Repository::discover(".") {
+        Ok(r) => r,
+        Err(_) => fail!("couldn't open the repository for the current directory!"),
+    };
+
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    let main = repo
+        .find_branch(&repo_config.main_branch, git2::BranchType::Local)
+        .unwrap()
+        .into_reference()
+        .peel_to_commit()
+        .unwrap();
+    let base = match repo.merge_base(head.id(), main.id()) {
+        Ok(b) => repo.find_commit(b).unwrap(),
+        Err(_) => fail!("failed to read merge base!"),
+    };
+
+    let mut revwalk = repo.revwalk().unwrap();
+    revwalk.push(main.id()).unwrap();
+    revwalk.hide(head.id()).unwrap();
+    let incoming: Vec<_> = revwalk
+        .filter_map(|oid| oid.ok())
+        .filter_map(|oid| repo.find_commit(oid).ok())
+        .collect();
+
+    if incoming.is_empty() {
+        println!("already up to date with {}", repo_config.main_branch);
+        return;
+    }
+
+    println!(
+        "{} incoming commit(s) from {}:\n",
+        incoming.len(),
+        repo_config.main_branch
+    );
+    for commit in &incoming {
+        println!(
+            "  {} {}",
+            &commit.id().to_string()[..7],
+            commit.summary().unwrap_or("")
+        );
+    }
+    println!();
+
+    // Build a tree of the worktree, like a snapshot would, but without writing the index or
+    // committing anything
+    let ours = worktree::tree(&repo).unwrap();
+
+    let ancestor = base.tree().unwrap();
+    let theirs = main.tree().unwrap();
+    let prediction = match strategy {
+        conf::SyncStrategy::Merge => repo
+            .merge_trees(&ancestor, &ours, &theirs, None)
+            .and_then(|merged| conflicted_paths(&merged))
+            .map(|conflicts| (None, conflicts)),
+        conf::SyncStrategy::Rebase => {
+            predict_rebase(&repo, &head, &main, &ours).map(|stop| match stop {
+                Some((stop, conflicts)) => (Some(stop), conflicts),
+                None => (None, Vec::new()),
+            })
+        }
+    };
+    let (stop, conflicts) = match prediction {
+        Ok(p) => p,
+        Err(e) => fail!("couldn't predict the sync: {}", e.message()),
+    };
+
+    let mut file_stats = Vec::new();
+    let incoming_diff = repo
+        .diff_tree_to_tree(Some(&ancestor), Some(&theirs), None)
+        .unwrap();
+    for delta in incoming_diff.deltas() {
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        if !conflicts.contains(&path) {
+            file_stats.push(("[clean]", path));
+        }
+    }
+    for conflict in &conflicts {
+        file_stats.push(("[conflict]", conflict.to_string()));
+    }
+    file_stats.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let max_width = file_stats.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    for (summary, filename) in file_stats {
+        println!("  {:>width$} {}", summary, filename, width = max_width);
+    }
+
+    match (strategy, stop) {
+        (conf::SyncStrategy::Merge, _) if conflicts.is_empty() => {
+            println!("\n`g2 sync` would merge cleanly")
+        }
+        (conf::SyncStrategy::Merge, _) => {
+            println!("\n`g2 sync` would conflict in {} file(s)", conflicts.len())
+        }
+        (conf::SyncStrategy::Rebase, None) => println!("\n`g2 sync` would rebase cleanly"),
+        (conf::SyncStrategy::Rebase, Some(stop)) => println!(
+            "\n`g2 sync` would stop rebasing at {}, with conflicts in {} file(s)",
+            stop,
+            conflicts.len()
+        ),
+    }
+}
+
+fn merge_main(
+    main_branch: &str,
+    strategy: conf::SyncStrategy,
+    workdir: Option<&str>,
+) -> (String, Result<i32, i32>) {
+    let args: &[&str] = match strategy {
+        conf::SyncStrategy::Merge => &["merge", "--no-edit", main_branch],
+        conf::SyncStrategy::Rebase => &["rebase", main_branch],
+    };
+    cmd::system("git", args, workdir, false)
+}
+
+enum SyncResult {
+    UpToDate,
+    Synced(conf::SyncStrategy),
+    Conflicts(usize),
+    Dirty,
+    InProgress,
+    Missing,
+    Failed,
+}
+
+impl std::fmt::Display for SyncResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyncResult::UpToDate => write!(f, "up to date"),
+            SyncResult::Synced(conf::SyncStrategy::Merge) => write!(f, "merged cleanly"),
+            SyncResult::Synced(conf::SyncStrategy::Rebase) => write!(f, "rebased cleanly"),
+            SyncResult::Conflicts(n) => write!(f, "conflicts in {} file(s)", n),
+            SyncResult::Dirty => write!(f, "skipped, has local changes"),
+            SyncResult::InProgress => write!(f, "skipped, sync in progress"),
+            SyncResult::Missing => write!(f, "skipped, worktree is missing"),
+            SyncResult::Failed => write!(f, "failed to sync"),
+        }
+    }
+}
+
+// Sync a single branch without snapshotting, so only branches without local changes are synced
+fn sync_branch(workdir: &str, main_branch: &str, strategy: conf::SyncStrategy) -> SyncResult {
+    if !std::path::Path::new(workdir).exists() {
+        return SyncResult::Missing;
+    }
+
+    match pending_sync(workdir) {
+        Ok(Some(_)) => return SyncResult::InProgress,
+        Ok(None) => (),
+        Err(_) => return SyncResult::Failed,
+    }
+
+    let (out, res) = cmd::system("git", &["status", "--porcelain"], Some(workdir), false);
+    if res.is_err() {
+        return SyncResult::Failed;
+    }
+    if !out.trim().is_empty() {
+        return SyncResult::Dirty;
+    }
+
+    let (_, res) = cmd::system(
+        "git",
+        &["merge-base", "--is-ancestor", main_branch, "HEAD"],
+        Some(workdir),
+        false,
+    );
+    if res.is_ok() {
+        return SyncResult::UpToDate;
+    }
+
+    let (_, res) = merge_main(main_branch, strategy, Some(workdir));
+    if res.is_ok() {
+        return SyncResult::Synced(strategy);
+    }
+
+    let (out, res) = cmd::system(
+        "git",
+        &["diff", "--name-only", "--diff-filter=U"],
+        Some(workdir),
+        false,
+    );
+    match out.lines().filter(|x| !x.trim().is_empty()).count() {
+        n if n > 0 && res.is_ok() => SyncResult::Conflicts(n),
+        _ => SyncResult::Failed,
+    }
+}
+
+fn sync_all(repo_filter: Option<&str>, strategy: Option<conf::SyncStrategy>, force_fetch: bool) {
+    let root_dir = conf::root_dir();
+    let config = conf::get_config();
+
+    if let Some(repo_name) = repo_filter {
+        if config.get_repo_config(repo_name).is_none() {
+            fail!("repo `{}` isn't cloned!", repo_name);
+        }
+    }
+
+    // Fetch each repository once, all of the branches share it
+    let mut fetched = Vec::new();
+    for repo_config in &config.repos {
+        if repo_filter.map(|r| r != repo_config.short_name()) == Some(true) {
+            continue;
+        }
+
+        if fetch::fetch_main(repo_config, force_fetch).is_err() {
+            eprintln!(
+                "couldn't fetch {}, skipping its branches",
+                repo_config.short_name()
+            );
+            continue;
+        }
+        fetched.push(repo_config.short_name());
+    }
+
+    // A broken worktree can't be recorded, but it'll fail to sync too, so it doesn't need to be
+    let mut entry = journal::Entry::new("sync --all");
+    for branch_config in &config.branches {
+        if fetched.contains(&branch_config.repo.as_str()) {
+            if let Err(e) = entry.try_record(branch_config) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    let mut handles = Vec::new();
+    for branch_config in &config.branches {
+        if !fetched.contains(&branch_config.repo.as_str()) {
+            continue;
+        }
+        let repo_config = config.get_repo_config(&branch_config.repo).unwrap();
+
+        let workdir = format!("{}/branches/{}", root_dir, branch_config.name);
+        let main_branch = repo_config.main_branch.clone();
+        let strategy = strategy.unwrap_or(repo_config.sync_strategy);
+        handles.push((
+            branch_config,
+            std::thread::spawn(move || sync_branch(&workdir, &main_branch, strategy)),
+        ));
+    }
+
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|(branch_config, handle)| {
+            let result = handle.join().unwrap_or(SyncResult::Failed);
+            (branch_config, result)
+        })
+        .collect();
+
+    let name_width = results.iter().map(|(b, _)| b.name.len()).max().unwrap_or(0);
+    let repo_width = results.iter().map(|(b, _)| b.repo.len()).max().unwrap_or(0);
+
+    let mut any_conflicts = false;
+    for (branch_config, result) in results {
+        if let SyncResult::Conflicts(_) = result {
+            any_conflicts = true;
+        }
+        println!(
+            "  {:<name_width$}  {:<repo_width$}  {}",
+            branch_config.name,
+            branch_config.repo,
+            result,
+            name_width = name_width,
+            repo_width = repo_width,
+        );
+    }
+
+    if any_conflicts {
+        eprintln!("\nto fix conflicts, go to each branch and run `g2 sync --continue`");
+    }
+}
+
+fn sync_continue(repo_config: &conf::RepoConfig, branch_config: &conf::BranchConfig) {
+    let strategy = match current_pending_sync() {
+        Some(s) => s,
+        None => fail!("there's no sync in progress!"),
+    };
+
+    check_conflict_markers(repo_config, "g2 sync --continue");
+
+    stage_all(&ignored_files(branch_config));
+
+    let (_, res) = match strategy {
+        conf::SyncStrategy::Merge => {
+            cmd::system("git", &["commit", "-n", "--no-edit"], None, false)
+        }
+        conf::SyncStrategy::Rebase => cmd::system(
+            "git",
+            &["-c", "core.editor=true", "rebase", "--continue"],
+            None,
+            false,
+        ),
+    };
+    if res.is_err() {
+        // The rebase may have stopped again on a later commit
+        report_conflicts();
+        eprintln!("\nfix the conflicts, then run `g2 sync --continue` again");
+        return;
+    }
+    restore_stash(&branch_config.branch_name);
+}
+
+fn sync_abort(branch_config: &conf::BranchConfig) {
+    let (_, res) = match current_pending_sync() {
+        Some(conf::SyncStrategy::Merge) => cmd::system("git", &["merge", "--abort"], None, false),
+        Some(conf::SyncStrategy::Rebase) => cmd::system("git", &["rebase", "--abort"], None, false),
+        None => fail!("there's no sync in progress!"),
+    };
+    if res.is_err() {
+        fail!("failed to abort the sync!");
+    }
+    restore_stash(&branch_config.branch_name);
+    println!("sync aborted, your branch is back where it was");
+}
+
+fn report_conflicts() {
+    let (out, res) = cmd::system(
+        "git",
+        &["diff", "--name-only", "--diff-filter=U"],
+        None,
+        false,
+    );
+    if res.is_err() {
+        fail!("failed to sync!");
+    }
+
+    let mut has_conflicts = false;
+    for conflict in out.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        if !has_conflicts {
+            has_conflicts = true;
+            eprintln!("There are some merge conflicts:\n");
+        }
+
+        eprintln!(" {}", conflict);
+    }
+
+    if !has_conflicts {
+        // If there are no conflicts and we failed to sync, then there's a problem
+        fail!("unexpectedly failed to sync!");
+    }
+}
+
+// Returns the snapshot commits on the current branch since it diverged from main, as well as
+// any snapshots kept under private refs, oldest first
+fn list_snapshots<'a>(
+    repo: &'a git2::Repository,
+    repo_config: &conf::RepoConfig,
+    branch_config: &conf::BranchConfig,
+) -> Vec<git2::Commit<'a>> {
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    let main = repo
+        .find_branch(&repo_config.main_branch, git2::BranchType::Local)
+        .unwrap()
+        .into_reference()
+        .peel_to_commit()
+        .unwrap();
+
+    let mut revwalk = repo.revwalk().unwrap();
+    revwalk
+        .set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)
+        .unwrap();
+    revwalk.push(head.id()).unwrap();
+    revwalk.hide(main.id()).unwrap();
+
+    let mut snapshots: Vec<_> = revwalk
+        .filter_map(|oid| oid.ok())
+        .filter_map(|oid| repo.find_commit(oid).ok())
+        .filter(|c| c.message().map(|m| m.trim()) == Some(branch_config.branch_name.as_str()))
+        .collect();
+
+    snapshots.extend(
+        snapshot_refs(repo, &branch_config.branch_name)
+            .into_iter()
+            .map(|(_, c)| c),
+    );
+    snapshots.sort_by_key(|c| c.time().seconds());
+    snapshots
+}
+
+fn open_current_repo() -> git2::Repository {
+    match git2::Repository::discover(".") {
+        Ok(r) => r,
+        Err(_) => fail!("couldn't open the repository for the current directory!"),
+    }
+}
+
+pub fn snapshots(args: &[String]) {
+    if !args.is_empty() {
+        fail!("`snapshots` doesn't take any arguments!");
+    }
+
+    let (repo_config, branch_config) = conf::get_current_dir_configs();
+    let repo = open_current_repo();
+    let snapshots = list_snapshots(&repo, &repo_config, &branch_config);
+    if snapshots.is_empty() {
+        println!("there are no snapshots on this branch yet");
+        return;
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let mut rows = Vec::new();
+    for (idx, commit) in snapshots.iter().enumerate().rev() {
+        let parent_tree = commit.parent(0).ok().map(|p| p.tree().unwrap());
+        let stats = repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree().unwrap()), None)
+            .and_then(|d| d.stats())
+            .unwrap();
+
+        rows.push((
+            format!("{}", idx + 1),
+            format_age(now - commit.time().seconds()),
+            format!(
+                "{} [+{}, -{}]",
+                &commit.id().to_string()[..7],
+                stats.insertions(),
+                stats.deletions()
+            ),
+            format!("{} file(s)", stats.files_changed()),
+        ));
+    }
+
+    let id_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let age_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    let stat_width = rows.iter().map(|r| r.2.len()).max().unwrap_or(0);
+    for (id, age, stat, files) in rows {
+        println!(
+            "  {:>id_width$}  {:<age_width$}  {:<stat_width$}  {}",
+            id,
+            age,
+            stat,
+            files,
+            id_width = id_width,
+            age_width = age_width,
+            stat_width = stat_width
+        );
+    }
+}
+
+// Brings back the worktree, or a single file, as of a snapshot. The current state is
+// snapshotted first, so a restore can always be undone by restoring that snapshot.
+// Resolves a snapshot number from `g2 snapshots`, or any commit, to a commit id
+fn find_snapshot(
+    repo_config: &conf::RepoConfig,
+    branch_config: &conf::BranchConfig,
+    target: &str,
+) -> String {
+    let repo = open_current_repo();
+    let snapshots = list_snapshots(&repo, repo_config, branch_config);
+    match target.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= snapshots.len() => snapshots[n - 1].id(),
+        _ => match repo
+            .revparse_single(target)
+            .and_then(|o| o.peel_to_commit())
+        {
+            Ok(c) => c.id(),
+            Err(_) => fail!("couldn't find snapshot `{}`, see `g2 snapshots`", target),
+        },
+    }
+    .to_string()
+}
+
+pub fn restore(args: &[String]) {
+    let (target, file) = match args {
+        [target] => (target, None),
+        [target, file] => (target, Some(file)),
+        _ => fail!("usage: g2 restore <snapshot> [file]"),
+    };
+
+    let (repo_config, branch_config) = conf::get_current_dir_configs();
+    let commit = find_snapshot(&repo_config, &branch_config, target);
+
+    journal::Entry::new("restore").record(&branch_config);
+    let current = snapshot(&repo_config, &branch_config);
+
+    match file {
+        Some(file) => {
+            let (_, res) = cmd::system("git", &["checkout", &commit, "--", file], None, false);
+            if res.is_err() {
+                // The file didn't exist at that point, so get rid of it
+                if std::fs::remove_file(file).is_err() {
+                    fail!("couldn't restore `{}`!", file);
+                }
+            }
+        }
+        None => {
+            let root = worktree_root();
+            let (_, res) = cmd::system(
+                "git",
+                &["checkout", &commit, "--", "."],
+                root.to_str(),
+                false,
+            );
+            if res.is_err() {
+                fail!("couldn't restore snapshot!");
+            }
+
+            // Leave the restored changes unstaged
+            let (_, res) = cmd::system("git", &["reset", "-q"], None, false);
+            if res.is_err() {
+                fail!("couldn't restore snapshot!");
+            }
+
+            // Remove files which were added after the snapshot
+            let (out, res) = cmd::system(
+                "git",
+                &["diff", "--name-only", "--diff-filter=A", &commit, &current],
+                None,
+                false,
+            );
+            if res.is_err() {
+                fail!("couldn't restore snapshot!");
+            }
+            for added in out.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                if std::fs::remove_file(root.join(added)).is_err() {
+                    fail!("couldn't remove `{}`!", added);
+                }
+            }
+        }
     }
-    out.trim().to_owned()
+
+    println!("restored {}", &commit[..7]);
 }
 
-pub fn diff(args: &[String]) {
-    let (repo_config, branch_config) = conf::get_current_dir_configs();
-    let base = merge_base(&branch_config.branch_name, &repo_config.main_branch);
+pub fn undo(args: &[String]) {
+    if !args.is_empty() {
+        fail!("`undo` doesn't take any arguments!");
+    }
 
-    let result = if args.len() > 0 {
-        let (_, result) = cmd::system("git", &["diff", &base, &args[0]], None, true);
-        result
-    } else {
-        let (_, result) = cmd::system("git", &["diff", &base], None, true);
-        result
+    let entry = match journal::latest() {
+        Some(e) => e,
+        None => fail!("there's nothing to undo!"),
     };
 
-    if result.is_err() {
-        fail!("unable to get diff!");
-    }
-}
-
-pub fn get_files() -> Vec<String> {
-    let (repo_config, branch_config) = conf::get_current_dir_configs();
-    let base = merge_base(&branch_config.branch_name, &repo_config.main_branch);
+    let mut config = conf::get_config();
+    let mut any_failures = false;
+    for state in &entry.branches {
+        if let Err(e) = journal::restore(&entry.id, state) {
+            eprintln!(
+                "failed to undo `{}` on {}: {}",
+                entry.command, state.config.name, e
+            );
+            any_failures = true;
+            continue;
+        }
 
-    let (out, result) = cmd::system(
-        "git",
-        &["--no-pager", "diff", &base, "--name-only"],
-        None,
-        false,
-    );
-    if result.is_err() {
-        fail!("unable to get diff! error: {}", out);
+        if state.created {
+            config.branches.retain(|b| b.name != state.config.name);
+        } else if config.get_branch_config(&state.config.name).is_none() {
+            config.branches.push(state.config.clone());
+        }
     }
+    for repo_config in &entry.repos {
+        if let Err(e) = journal::restore_repo(repo_config) {
+            eprintln!(
+                "failed to undo `{}` on {}: {}",
+                entry.command,
+                repo_config.short_name(),
+                e
+            );
+            any_failures = true;
+            continue;
+        }
 
-    let mut output: Vec<_> = out
-        .split('\n')
-        .map(|x| x.trim().to_string())
-        .filter(|x| !x.is_empty())
-        .collect();
-
-    let (out, result) = cmd::system(
-        "git",
-        &["ls-files", "--others", "--exclude-standard"],
-        None,
-        false,
-    );
-    if result.is_err() {
-        fail!("unable to get diff! error: {}", out);
+        config.repos.retain(|r| r.path != repo_config.path);
+        config.repos.push(repo_config.clone());
     }
+    conf::set_config(&config);
 
-    for item in out
-        .split('\n')
-        .map(|x| x.trim().to_string())
-        .filter(|x| !x.is_empty())
-    {
-        output.push(item);
+    if any_failures {
+        fail!("\nsome branches couldn't be restored, run `g2 undo` again to retry");
     }
 
-    output.sort();
-    output
-}
-
-pub fn files() {
-    for result in get_files() {
-        println!("{}", result);
-    }
+    entry.discard();
+    println!("undid `g2 {}`", entry.command);
 }
 
-fn snapshot(msg: &str) {
-    // Check that there are no SCM change markers in the files to add
-    let mut conflicts = Vec::new();
-    for file in get_files() {
-        if let Ok(s) = std::fs::read_to_string(&file) {
-            if s.contains(&format!("<<<{}<<<<", "")) || s.contains(&format!(">>>{}>>>>", "")) {
-                conflicts.push(file);
+// Watches the current worktree, or every worktree with `--all`, and snapshots it to a private
+// ref once edits have stopped for a while
+pub fn watch(args: &[String]) {
+    let mut all = false;
+    let mut delay = 10;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--all" => all = true,
+            "--delay" => {
+                delay = match iter.next().and_then(|d| d.parse::<u64>().ok()) {
+                    Some(d) => d,
+                    None => fail!("`--delay` needs a number of seconds"),
+                };
             }
+            _ => fail!("unknown argument `{}`", arg),
         }
     }
 
-    if !conflicts.is_empty() {
-        println!("the following files contain SCM change markers:\n");
-        for conflict in conflicts {
-            println!("  {}", conflict);
-        }
-        fail!("\nresolve the conflicts first, then run `g2 sync` again");
+    let root_dir = conf::root_dir();
+    let workdirs: Vec<String> = if all {
+        conf::get_config()
+            .branches
+            .iter()
+            .map(|b| format!("{}/branches/{}", root_dir, b.name))
+            .filter(|w| std::path::Path::new(w).exists())
+            .collect()
+    } else {
+        let (_, branch_config) = conf::get_current_dir_configs();
+        vec![format!("{}/branches/{}", root_dir, branch_config.name)]
+    };
+    if workdirs.is_empty() {
+        fail!("there are no branches to watch!");
     }
 
-    let mut c = std::process::Command::new("git");
-    c.arg("add").arg(".");
-    unwrap_or_fail(get_stdout(c));
+    let mut watcher = match watch::Watcher::new(&workdirs) {
+        Ok(w) => w,
+        Err(e) => fail!("{}", e),
+    };
+    println!(
+        "watching {} branch(es), snapshotting after {}s without edits",
+        workdirs.len(),
+        delay
+    );
 
-    let mut c = std::process::Command::new("git");
-    c.arg("commit").arg("-n").arg("-m").arg(msg);
-    get_status(c);
-}
+    let mut latest: std::collections::HashMap<String, String> = Default::default();
+    loop {
+        let changed = match watcher.wait(std::time::Duration::from_secs(delay)) {
+            Ok(c) => c,
+            Err(e) => fail!("{}", e),
+        };
 
-pub fn sync() {
-    let (repo_config, branch_config) = conf::get_current_dir_configs();
+        for workdir in changed {
+            // The branch may have been cleaned up since
+            if std::env::set_current_dir(&workdir).is_err() {
+                continue;
+            }
+            let (repo_config, branch_config) = conf::get_current_dir_configs();
+            match pending_sync(&workdir) {
+                Ok(None) => (),
+                Ok(Some(_)) => {
+                    println!("{}: sync in progress, skipping", branch_config.name);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", branch_config.name, e);
+                    continue;
+                }
+            }
+            if get_files().is_empty() {
+                continue;
+            }
+            if !find_conflict_markers(&repo_config).is_empty() {
+                println!(
+                    "{}: files contain SCM change markers, skipping",
+                    branch_config.name
+                );
+                continue;
+            }
 
-    // Fetch origin. Can't do this with libgit2 because it requires authentication
-    let mut c = std::process::Command::new("git");
-    c.arg("fetch").arg("-q").arg("origin").arg(format!(
-        "{}:{}",
-        &repo_config.main_branch, &repo_config.main_branch
-    ));
-    unwrap_or_fail(get_stdout(c));
+            let id = snapshot_to_ref(&branch_config.branch_name, &ignored_files(&branch_config));
+            if latest.get(&workdir) != Some(&id) {
+                println!("{}: snapshotted {}", branch_config.name, &id[..7]);
+                latest.insert(workdir, id);
+            }
+        }
+    }
+}
 
-    // Snapshot so we can merge incoming changes
-    snapshot(&branch_config.branch_name);
+// The configs of every repo under ~/.g2/repos. Repos missing from g2.toml get a default config,
+// using the branch their HEAD points to as main.
+fn all_repos() -> Vec<conf::RepoConfig> {
+    let mut repos = conf::get_config().repos;
+    let repos_dir = format!("{}/repos", conf::root_dir());
+    let entries = match std::fs::read_dir(&repos_dir) {
+        Ok(e) => e,
+        Err(_) => return repos,
+    };
 
-    // Try to merge
-    let (_, res) = cmd::system("git", &["merge", &repo_config.main_branch], None, false);
-    if res.is_err() {
-        // There may have been a conflict
-        let (out, res) = cmd::system(
-            "git",
-            &["diff", "--name-only", "--diff-filter=U"],
-            None,
-            false,
-        );
-        if res.is_err() {
-            fail!("failed to sync!");
+    let mut dirs: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_string_lossy().to_string())
+        .collect();
+    dirs.sort();
+    for dir in dirs {
+        let name = dir.rsplit('/').next().unwrap();
+        if repos.iter().any(|r| r.short_name() == name) {
+            continue;
+        }
+        let repo = match git2::Repository::open_bare(&dir) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        let main_branch = repo
+            .find_reference("HEAD")
+            .ok()
+            .and_then(|h| h.symbolic_target().map(|t| t.to_string()))
+            .map(|t| t.trim_start_matches("refs/heads/").to_string());
+        if let Some(main_branch) = main_branch {
+            repos.push(conf::RepoConfig::new(dir.clone(), main_branch));
         }
+    }
+    repos
+}
 
-        let mut has_conflicts = false;
-        for conflict in out.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
-            if !has_conflicts {
-                has_conflicts = true;
-                eprintln!("There are some merge conflicts:\n");
-            }
+pub fn fetch(args: &[String]) {
+    let all = match args {
+        [] => false,
+        [x] if x == "--all" => true,
+        _ => fail!("usage: g2 fetch [--all]"),
+    };
 
-            eprintln!(" {}", conflict);
-        }
+    let repos = if all {
+        all_repos()
+    } else {
+        vec![conf::get_current_dir_configs().0]
+    };
 
-        if !has_conflicts {
-            // If there are no conflicts and we failed to sync, then there's a problem
-            fail!("unexpectedly failed to sync!");
+    let mut any_failures = false;
+    for repo_config in &repos {
+        if fetch::fetch_main(repo_config, true).is_err() {
+            eprintln!("couldn't fetch {}!", repo_config.short_name());
+            any_failures = true;
         }
+    }
 
-        eprintln!("\nfix the conflicts, then run `g2 sync` again");
+    if any_failures {
+        fail!();
     }
 }
 
-pub fn upload() {
-    let (repo_config, branch_config) = conf::get_current_dir_configs();
-    snapshot(&branch_config.branch_name);
+pub fn upload(args: &[String]) {
+    let mut hooks = false;
+    for arg in args {
+        match arg.as_str() {
+            "--hooks" => hooks = true,
+            _ => fail!("unknown argument `{}`", arg),
+        }
+    }
 
-    let (_, result) = cmd::system(
-        "git",
-        &["push", "--set-upstream", "origin", "HEAD"],
-        None,
-        true,
-    );
-    if result.is_err() {
+    let (repo_config, branch_config) = conf::get_current_dir_configs();
+    journal::Entry::new("upload").record(&branch_config);
+    let excluded = prepare_snapshot(&repo_config, &branch_config);
+    if hooks || repo_config.run_hooks {
+        let base = merge_base(&branch_config.branch_name, &repo_config.main_branch);
+        run_commit_hooks(&branch_config.branch_name, &base, &excluded);
+    }
+    if repo_config.snapshot_mode == conf::SnapshotMode::Ref {
+        snapshot_to_ref(&branch_config.branch_name, &excluded);
+    }
+    // In ref mode, snapshots don't touch the branch, so this is where the changes get committed
+    commit_all(&branch_config.branch_name, &excluded);
+
+    // Rebasing rewrites history that may already be pushed, so the push has to be forced. That
+    // depends on how the branch was synced, not on the repo's default strategy.
+    let repo = open_current_repo();
+    if let Err(e) = remote::seed_tracking_ref(&repo, &branch_config.branch_name) {
+        eprintln!("couldn't check what was pushed before: {}", e.message());
+    }
+    let force_with_lease = remote::rewrites_history(&repo, &branch_config.branch_name);
+    if remote::push(".", &branch_config.branch_name, force_with_lease).is_err() {
         fail!("failed to push to remote!");
     }
+    record_upload(&branch_config.branch_name);
 
     // Check whether a pull request exists
     let (_, result) = cmd::system("gh", &["pr", "view"], None, false);
@@ -435,7 +2214,7 @@ pub fn upload() {
     let description = std::fs::read_to_string(&filename).unwrap();
     let mut description_iter = description.lines();
     let mut title = String::new();
-    while let Some(line) = description_iter.next() {
+    for line in description_iter.by_ref() {
         let line = line.trim();
         if line.is_empty() || line.starts_with('#') {
             continue;
@@ -490,10 +2269,12 @@ pub fn upload() {
 pub fn clean() {
     let root_dir = conf::root_dir();
     let mut config = conf::get_config();
+    let mut entry = journal::Entry::new("clean");
     config.branches.retain(|branch| {
         let branch_dir = format!("{}/branches/{}", root_dir, branch.name);
         if !std::path::Path::new(&branch_dir).exists() {
             println!("branch {} doesn't exist, cleaning it up", branch.name);
+            entry.record(branch);
             return false;
         }
 
@@ -506,29 +2287,40 @@ pub fn clean() {
 
         if output.contains("MERGED\n") || output.contains("CLOSED\n") {
             println!("branch {} is already merged!", branch.branch_name);
+            entry.record(branch);
 
             // Rename the branch to avoid name conflicts later
+            let repo_dir = format!("{}/repos/{}", root_dir, branch.repo);
+            let renamed = format!(
+                "{}__cleaned_{}",
+                branch.branch_name,
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            );
             let (_, res) = cmd::system(
                 "git",
-                &[
-                    "branch",
-                    "-m",
-                    &branch.branch_name,
-                    &format!(
-                        "{}__cleaned_{}",
-                        branch.branch_name,
-                        std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs()
-                    ),
-                ],
-                Some(&format!("{}/repos/{}", root_dir, branch.repo)),
+                &["branch", "-m", &branch.branch_name, &renamed],
+                Some(&repo_dir),
                 false,
             );
 
             if res.is_err() {
                 eprintln!("failed to rename branch, proceeding anyway...");
+            } else {
+                entry.record_rename(branch, &renamed);
+            }
+
+            // The snapshots were only kept to restore work in progress, but `g2 undo` should
+            // bring them back
+            if let Ok(repo) = git2::Repository::open_bare(&repo_dir) {
+                let prefix = snapshot_ref_prefix(&branch.branch_name);
+                let refs: Vec<String> = snapshot_refs(&repo, &branch.branch_name)
+                    .into_iter()
+                    .map(|(n, _)| format!("{}{}", prefix, n))
+                    .collect();
+                entry.set_aside_refs(&repo_dir, &refs);
             }
 
             std::fs::remove_dir_all(&branch_dir).unwrap();
@@ -541,91 +2333,123 @@ pub fn clean() {
     conf::set_config(&config);
 }
 
-pub fn status() {
-    let root_dir = conf::root_dir();
+pub fn status(args: &[String]) {
+    let json = parse_json_flag(args);
     let (repo_config, branch_config) = conf::get_current_dir_configs();
-    let base = merge_base(&branch_config.branch_name, &repo_config.main_branch);
+    let files = file_changes(true, true);
+    let sync = match status::sync_state(
+        &open_current_repo(),
+        &branch_config.branch_name,
+        &repo_config.main_branch,
+        &branch_config.ignored_files,
+    ) {
+        Ok(s) => s,
+        Err(e) => fail!("couldn't compare the branch to main: {}", e.message()),
+    };
+    let pull_request = status::pull_request();
+
+    if json {
+        print_json(&status::BranchStatus {
+            name: branch_config.name,
+            branch: branch_config.branch_name,
+            repo: branch_config.repo,
+            pull_request,
+            sync: Some(sync),
+            files: Some(files),
+        });
+        return;
+    }
 
-    // Check if this PR exists
-    let (out, res) = cmd::system(
-        "gh",
-        &["pr", "view", "--json", "number", "--jq", ".number"],
-        None,
-        false,
-    );
-    if res.is_err() {
-        println!("Local branch ({})", branch_config.branch_name);
-    } else {
-        let pr_number = out.trim();
-
-        let (out, res) = cmd::system(
-            "gh",
-            &[
-                "api",
-                "-X",
-                "GET",
-                &format!("/repos/:owner/:repo/pulls/{}", pr_number),
-                "--jq",
-                ".title, .html_url",
-            ],
-            None,
-            false,
-        );
-        if res.is_err() {
-            println!("Local branch ({})", branch_config.branch_name);
-        } else {
-            let mut lines = out.lines().map(|x| x.trim());
-            let title = lines.next().unwrap();
-            let url = lines.next().unwrap();
+    match pull_request {
+        Some(pr) => println!("{} ({})", pr.title, pr.url),
+        None => println!("Local branch ({})", branch_config.branch_name),
+    }
+    println!("  {}", sync.summary());
 
-            println!("{} ({})", title, url);
-        }
+    let summaries: Vec<_> = files.iter().map(|f| f.summary()).collect();
+    let max_numstats = summaries.iter().map(|s| s.len()).max().unwrap_or(0);
+    for (summary, file) in summaries.iter().zip(&files) {
+        println!(
+            "  {:>width$} {}",
+            summary,
+            file.display_path(),
+            width = max_numstats
+        );
     }
+}
 
-    let mut file_stats = Vec::new();
-    for file in get_files() {
-        // First, check if the file is deleted in current branch.
-        let path = format!("{}/branches/{}/{}", root_dir, branch_config.name, file);
-        if !std::path::Path::new(&path).exists() {
-            file_stats.push(("[deleted]".to_string(), file));
-            continue;
-        }
+// Lists every branch, along with its pull request if there is one
+pub fn branches(args: &[String]) {
+    let json = parse_json_flag(args);
+    let config = conf::get_config();
 
-        let (out, result) = cmd::system("git", &["diff", "--numstat", &base, &file], None, false);
-        if result.is_err() {
-            fail!("couldn't run git diff!");
-        }
-        let numbers: Vec<usize> = out
-            .split_whitespace()
-            .filter_map(|x| x.parse().ok())
-            .collect();
-        if numbers.len() != 2 {
-            file_stats.push((String::from("[new]"), file));
-            continue;
+    let mut records = Vec::new();
+    for repo_config in &config.repos {
+        let mut pull_requests = status::pull_requests(&fetch::repo_dir(repo_config));
+        for branch_config in config
+            .branches
+            .iter()
+            .filter(|b| b.repo == repo_config.short_name())
+        {
+            let pull_request = pull_requests
+                .iter()
+                .position(|(head, _)| head == &branch_config.branch_name)
+                .map(|idx| pull_requests.remove(idx).1);
+            records.push(status::BranchStatus {
+                name: branch_config.name.clone(),
+                branch: branch_config.branch_name.clone(),
+                repo: branch_config.repo.clone(),
+                pull_request,
+                sync: None,
+                files: None,
+            });
         }
-
-        let num_summary = match (numbers[0], numbers[1]) {
-            (0, 0) => String::from("[new]"),
-            (x, 0) => format!("[+{}]", x),
-            (0, x) => format!("[-{}]", x),
-            (x, y) => format!("[+{}, -{}]", x, y),
-        };
-
-        file_stats.push((num_summary, file));
     }
 
-    let max_numstats = file_stats.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    if json {
+        print_json(&records);
+        return;
+    }
 
-    for (num_summary, filename) in file_stats {
+    let rows: Vec<_> = records
+        .iter()
+        .map(|r| {
+            let pr = match &r.pull_request {
+                Some(pr) => format!("#{} {}", pr.number, pr.state.to_lowercase()),
+                None => String::new(),
+            };
+            (&r.name, &r.repo, pr)
+        })
+        .collect();
+    let name_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let repo_width = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    for (name, repo, pr) in rows {
         println!(
-            "  {:>width$} {}",
-            num_summary,
-            filename,
-            width = max_numstats
+            "  {:<name_width$}  {:<repo_width$}  {}",
+            name,
+            repo,
+            pr,
+            name_width = name_width,
+            repo_width = repo_width,
         );
     }
 }
 
+fn parse_json_flag(args: &[String]) -> bool {
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => fail!("unknown argument `{}`", arg),
+        }
+    }
+    json
+}
+
+fn print_json<T: serde::Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
 pub fn adopt(args: &[String]) {
     let mut config = conf::get_config();
     let (repo_name, branch_name) = if args.len() == 2 {
@@ -651,33 +2475,25 @@ pub fn adopt(args: &[String]) {
         ),
     };
 
-    // Fetch origin. Can't do this with libgit2 because it requires authentication
-    let (_, result) = cmd::system(
-        "git",
-        &[
-            "fetch",
-            "-q",
-            "origin",
-            &format!("{}:{}", &repo_config.main_branch, &repo_config.main_branch),
-        ],
-        Some(&format!("{}/repos/{}", root_dir, repo_name)),
-        true,
-    );
-    if result.is_err() {
+    if fetch::fetch_main(repo_config, false).is_err() {
         fail!("couldn't fetch origin!");
     }
 
-    // Fetch target branch.
-    let (_, result) = cmd::system(
-        "git",
-        &[
-            "fetch",
-            "-q",
-            "origin",
-            &format!("{}:{}", &branch_name, &branch_name),
-        ],
-        Some(&format!("{}/repos/{}", root_dir, repo_name)),
-        true,
+    let dir_name = config.adopt_branch(branch_name.to_string(), repo_name.to_string());
+    let branch_config = config.get_branch_config(&dir_name).unwrap();
+    journal::Entry::new("adopt").record_created(branch_config);
+
+    // Fetch target branch, and remember where it is on origin so that uploads can tell whether
+    // they need to force push
+    let refspec = format!("refs/heads/{}:refs/heads/{}", &branch_name, &branch_name);
+    let tracking = format!(
+        "+refs/heads/{}:{}",
+        &branch_name,
+        remote::tracking_ref(&branch_name)
+    );
+    let result = remote::fetch(
+        &format!("{}/repos/{}", root_dir, repo_name),
+        &[&refspec, &tracking],
     );
     if result.is_err() {
         eprintln!("is the branch already checked out locally? continuing with local branch...");
@@ -685,9 +2501,6 @@ pub fn adopt(args: &[String]) {
 
     let repo = git2::Repository::open_bare(format!("{}/repos/{}", root_dir, repo_name)).unwrap();
 
-    // Check that the branch doesn't already exist
-    let dir_name = config.adopt_branch(branch_name.to_string(), repo_name.to_string());
-
     let branch_ref = repo
         .find_branch(&branch_name, git2::BranchType::Local)
         .unwrap()
@@ -708,36 +2521,169 @@ pub fn adopt(args: &[String]) {
     cmd::teleport(&path);
 }
 
+// Whether `path`, relative to the root of the worktree, is one of the files that `pattern` refers
+// to. Patterns are globs, directories or individual files.
+fn matches_path(pattern: &str, path: &str) -> bool {
+    if pattern.contains(|c| "*?[".contains(c)) {
+        return match glob::Pattern::new(pattern) {
+            Ok(g) => g.matches(path),
+            Err(e) => fail!("invalid glob `{}`: {}", pattern, e),
+        };
+    }
+    pattern.is_empty()
+        || path == pattern
+        || (path.starts_with(pattern) && path[pattern.len()..].starts_with('/'))
+}
+
+// The changed files that the paths on the command line refer to, relative to the root of the
+// worktree. Reverting either side of a rename reverts both.
+fn revert_targets(changes: &[status::FileChange], patterns: &[String]) -> Vec<String> {
+    let mut output = Vec::new();
+    for pattern in patterns {
+        let mut found = false;
+        for change in changes {
+            let mut paths = vec![&change.path];
+            if change.kind == status::ChangeKind::Renamed {
+                paths.extend(&change.old_path);
+            }
+            if paths.iter().any(|p| matches_path(pattern, p)) {
+                found = true;
+                output.extend(paths.into_iter().cloned());
+            }
+        }
+        if !found {
+            fail!("`{}` doesn't have any changes to revert!", pattern);
+        }
+    }
+    output.sort();
+    output.dedup();
+    output
+}
+
+// Brings `files` back to how they were in `base`, removing the ones which didn't exist yet. The
+// reverted files are left unstaged.
+fn revert_files(base: &str, files: &[String], interactive: bool) {
+    let repo = open_current_repo();
+    let tree = match repo.revparse_single(base).and_then(|o| o.peel_to_tree()) {
+        Ok(t) => t,
+        Err(_) => fail!("couldn't read `{}`!", base),
+    };
+    let (existing, added): (Vec<&String>, Vec<&String>) = files
+        .iter()
+        .partition(|f| tree.get_path(std::path::Path::new(f)).is_ok());
+
+    let root = worktree_root();
+    let workdir = root.to_str();
+    if !existing.is_empty() {
+        let mut args = vec!["checkout"];
+        if interactive {
+            args.push("-p");
+        }
+        args.extend(&[base, "--"]);
+        args.extend(existing.iter().map(|f| f.as_str()));
+        let (_, res) = cmd::system("git", &args, workdir, interactive);
+        if res.is_err() {
+            fail!("couldn't revert files!");
+        }
+    }
+
+    for file in added {
+        if interactive && !tui::confirm(&format!("remove new file `{}`?", file)) {
+            continue;
+        }
+        match std::fs::remove_file(root.join(file)) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(_) => fail!("couldn't remove `{}`!", file),
+        }
+    }
+
+    let mut args = vec!["reset", "-q", "--"];
+    args.extend(files.iter().map(|f| f.as_str()));
+    let (_, res) = cmd::system("git", &args, workdir, false);
+    if res.is_err() {
+        fail!("couldn't unstage reverted files!");
+    }
+}
+
 pub fn revert(args: &[String]) {
-    if args.len() != 1 {
-        fail!("you must provide exactly one argument, the filename to revert");
+    use std::io::IsTerminal;
+
+    let usage = "usage: g2 revert [-i] [--to <main|snapshot|commit> | --to-upload] \
+        <path>... | --all [--yes]";
+    let mut interactive = false;
+    let mut all = false;
+    let mut yes = false;
+    let mut to = None;
+    let mut to_upload = false;
+    let mut paths = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-i" | "--interactive" => interactive = true,
+            "--all" => all = true,
+            "-y" | "--yes" => yes = true,
+            "--to" => match iter.next() {
+                Some(target) => to = Some(target.as_str()),
+                None => fail!("`--to` needs `main`, a snapshot or a commit"),
+            },
+            "--to-upload" => to_upload = true,
+            "--" => paths.extend(iter.by_ref().cloned()),
+            a if a.starts_with('-') => fail!("unknown argument `{}`\n{}", a, usage),
+            _ => paths.push(arg.clone()),
+        }
+    }
+    if all != paths.is_empty() {
+        fail!("{}", usage);
+    }
+    if to.is_some() && to_upload {
+        fail!("`--to` and `--to-upload` can't be used together");
+    }
+    if interactive && !std::io::stdin().is_terminal() {
+        fail!("`g2 revert -i` needs a terminal!");
     }
-    let name = &args[0];
 
     let (repo_config, branch_config) = conf::get_current_dir_configs();
-    let base = merge_base(&branch_config.branch_name, &repo_config.main_branch);
+    let base = match to {
+        // The latest main, rather than the version the branch forked from
+        Some("main") => find_snapshot(&repo_config, &branch_config, &repo_config.main_branch),
+        Some(target) => find_snapshot(&repo_config, &branch_config, target),
+        None if to_upload => {
+            let repo = open_current_repo();
+            let id = latest_upload(&repo, &branch_config.branch_name).id();
+            id.to_string()
+        }
+        None => merge_base(&branch_config.branch_name, &repo_config.main_branch),
+    };
 
-    let (_, res) = cmd::system("git", &["checkout", &base, name], None, false);
-    if res.is_err() {
-        // Check if the file existed in the version.
-        let (_, res) = cmd::system(
-            "git",
-            &["cat-file", "-e", &format!("{}:{}", base, name)],
-            None,
-            false,
+    // Files the branch has chosen to leave out of snapshots aren't touched, even by `--all`
+    let changes = match git2::Oid::from_str(&base) {
+        Ok(oid) => file_changes_since(oid, false, false),
+        Err(_) => fail!("couldn't read `{}`!", base),
+    };
+    let patterns = if all {
+        vec![String::new()]
+    } else {
+        paths.iter().map(|p| repo_path(p)).collect()
+    };
+    let files = revert_targets(&changes, &patterns);
+
+    if all && !yes {
+        if !std::io::stdin().is_terminal() {
+            fail!("pass `--yes` to revert every file without being asked");
+        }
+        let prompt = format!(
+            "revert all {} changed files to {}? this can be undone with `g2 undo`",
+            files.len(),
+            &base[..7]
         );
-        if res.is_err() {
-            // If the file didn't exist previously, delete it
-            match std::fs::remove_file(name) {
-                Ok(_) => (),
-                Err(_) => {
-                    fail!("couldn't revert file! does that file exist?");
-                }
-            }
-        } else {
-            fail!("couldn't revert file!");
+        if !tui::confirm(&prompt) {
+            fail!("nothing was reverted");
         }
     }
+
+    journal::Entry::new("revert").record(&branch_config);
+    revert_files(&base, &files, interactive);
 }
 
 pub fn check() {
@@ -835,12 +2781,26 @@ pub fn check() {
     }
 }
 
+fn format_age(secs: i64) -> String {
+    let (n, unit) = match secs.max(0) {
+        s if s < 60 => return String::from("just now"),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 60 * 60 * 24 => (s / (60 * 60), "hour"),
+        s => (s / (60 * 60 * 24), "day"),
+    };
+    if n == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", n, unit)
+    }
+}
+
 fn format_description(input: &str) -> String {
     let mut output = String::new();
     let mut prev_text = false;
     for line in input.lines().map(|l| l.trim()) {
         if line.starts_with(char::is_numeric) || line.starts_with('-') || line.starts_with('[') {
-            output.push_str(&line);
+            output.push_str(line);
             output.push('\n');
         } else if line.is_empty() {
             if prev_text {
@@ -864,6 +2824,42 @@ fn format_description(input: &str) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("src/main.rs"), "src/main.rs");
+        assert_eq!(normalize_path("src/../README.md"), "README.md");
+        assert_eq!(normalize_path("src/./a//b.rs"), "src/a/b.rs");
+        assert_eq!(normalize_path("src/"), "src");
+    }
+
+    #[test]
+    fn test_matches_path() {
+        assert!(matches_path("src/main.rs", "src/main.rs"));
+        assert!(matches_path("src", "src/main.rs"));
+        assert!(!matches_path("src", "src2/main.rs"));
+        assert!(matches_path("src/*.rs", "src/main.rs"));
+        assert!(!matches_path("*.md", "src/main.rs"));
+        assert!(matches_path("", "README.md"));
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path("src/main.rs", ""), "src/main.rs");
+        assert_eq!(relative_path("src/main.rs", "src/"), "main.rs");
+        assert_eq!(relative_path("README.md", "src/"), "../README.md");
+        assert_eq!(relative_path("src/a/b.rs", "src/c/"), "../a/b.rs");
+        // A file with the same name as the directory
+        assert_eq!(relative_path("src", "src/"), "../src");
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(5), "just now");
+        assert_eq!(format_age(60), "1 minute ago");
+        assert_eq!(format_age(60 * 60 * 5 + 30), "5 hours ago");
+        assert_eq!(format_age(60 * 60 * 24 * 3), "3 days ago");
+    }
+
     #[test]
     fn test_pr_description() {
         let description = "
@@ -6,6 +6,17 @@ use serde::{Deserialize, Serialize};
 pub struct Config {
     #[serde(default)]
     branch_prefix: String,
+    // How long a fetch of a repo's main branch is reused by later commands
+    #[serde(default = "default_fetch_freshness_secs")]
+    pub fetch_freshness_secs: u64,
+    // A pager that `g2 diff` pipes its output through, e.g. `delta`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default)]
+    pub diff_pager: String,
+    // An external diff program that `g2 diff` hands each file to, e.g. `difft`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default)]
+    pub diff_tool: String,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub repos: Vec<RepoConfig>,
@@ -14,23 +25,121 @@ pub struct Config {
     pub branches: Vec<BranchConfig>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BranchConfig {
     pub name: String,
     pub branch_name: String,
     pub repo: String,
+    // New untracked files which were left out of snapshots when asked about
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub ignored_files: Vec<String>,
+    // New untracked files which were included. In ref mode they stay untracked until upload,
+    // so this stops them being asked about again.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub included_files: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RepoConfig {
     pub path: String,
     pub main_branch: String,
+    #[serde(default)]
+    pub sync_strategy: SyncStrategy,
+    #[serde(default)]
+    pub snapshot_mode: SnapshotMode,
+    // Glob patterns for files which are allowed to contain conflict markers, e.g. test fixtures
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub allow_conflict_markers: Vec<String>,
+    // Glob patterns for files which may look like they contain secrets, or are allowed to be
+    // larger than `max_file_size`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub allow_secrets: Vec<String>,
+    // Files larger than this many bytes aren't committed
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    // What to do with new untracked files when there's nobody to ask
+    #[serde(default)]
+    pub untracked_policy: UntrackedPolicy,
+    // Whether `g2 upload` runs the repo's pre-commit and commit-msg hooks before pushing
+    #[serde(default)]
+    pub run_hooks: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncStrategy {
+    #[default]
+    Merge,
+    Rebase,
+}
+
+// Where snapshots are kept: as commits on the branch itself, or under private refs in
+// `refs/g2/snapshots/<branch>/<n>`, leaving the branch alone until upload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotMode {
+    #[default]
+    Commit,
+    Ref,
+}
+
+// How snapshots treat untracked files that haven't been seen before, when not running in a
+// terminal: add them, leave them out this time, or refuse to snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UntrackedPolicy {
+    #[default]
+    Include,
+    Skip,
+    Fail,
 }
 
 impl RepoConfig {
+    pub fn new(path: String, main_branch: String) -> Self {
+        RepoConfig {
+            path,
+            main_branch,
+            sync_strategy: SyncStrategy::default(),
+            snapshot_mode: SnapshotMode::default(),
+            allow_conflict_markers: Vec::new(),
+            allow_secrets: Vec::new(),
+            max_file_size: default_max_file_size(),
+            untracked_policy: UntrackedPolicy::default(),
+            run_hooks: false,
+        }
+    }
+
     pub fn short_name(&self) -> &str {
         self.path.rsplit('/').next().unwrap()
     }
+
+    pub fn allows_conflict_markers(&self, file: &str) -> bool {
+        matches_any(&self.allow_conflict_markers, file)
+    }
+
+    pub fn allows_secrets(&self, file: &str) -> bool {
+        matches_any(&self.allow_secrets, file)
+    }
+}
+
+fn matches_any(patterns: &[String], file: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        glob::Pattern::new(pattern)
+            .map(|p| p.matches(file))
+            .unwrap_or(false)
+    })
+}
+
+fn default_fetch_freshness_secs() -> u64 {
+    300
+}
+
+fn default_max_file_size() -> u64 {
+    5 * 1024 * 1024
 }
 
 impl Config {
@@ -39,6 +148,9 @@ impl Config {
             branches: Vec::new(),
             repos: Vec::new(),
             branch_prefix: String::new(),
+            fetch_freshness_secs: default_fetch_freshness_secs(),
+            diff_pager: String::new(),
+            diff_tool: String::new(),
         }
     }
 
@@ -51,21 +163,25 @@ impl Config {
     }
 
     pub fn take_configs(self, branch_name: &str) -> Option<(RepoConfig, BranchConfig)> {
-        let branch_config = match self.branches.into_iter().find(|x| x.name == branch_name) {
-            Some(b) => b,
-            None => return None,
-        };
-        let repo_config = match self
+        let branch_config = self.branches.into_iter().find(|x| x.name == branch_name)?;
+        let repo_config = self
             .repos
             .into_iter()
-            .find(|x| x.short_name() == branch_config.repo)
-        {
-            Some(r) => r,
-            None => return None,
-        };
+            .find(|x| x.short_name() == branch_config.repo)?;
         Some((repo_config, branch_config))
     }
 
+    pub fn set_branch_config(&mut self, branch_config: BranchConfig) {
+        match self
+            .branches
+            .iter_mut()
+            .find(|b| b.name == branch_config.name)
+        {
+            Some(b) => *b = branch_config,
+            None => self.branches.push(branch_config),
+        }
+    }
+
     pub fn add_branch(&mut self, name: String, repo: String) -> String {
         let branch_name = format!("{}{}", self.branch_prefix, name);
 
@@ -74,6 +190,8 @@ impl Config {
             name,
             repo,
             branch_name: branch_name.clone(),
+            ignored_files: Vec::new(),
+            included_files: Vec::new(),
         });
         branch_name
     }
@@ -88,13 +206,15 @@ impl Config {
             name: name.clone(),
             repo,
             branch_name,
+            ignored_files: Vec::new(),
+            included_files: Vec::new(),
         });
         name
     }
 
     pub fn add_repo(&mut self, path: String, main_branch: String) {
         self.repos.retain(|s| s.path != path);
-        self.repos.push(RepoConfig { path, main_branch })
+        self.repos.push(RepoConfig::new(path, main_branch))
     }
 }
 
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cmd, conf, remote};
+
+// Records when each repo's main branch was last fetched, kept separately from g2.toml so that
+// commands holding onto a loaded config don't overwrite it
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FetchLog {
+    #[serde(default)]
+    repos: BTreeMap<String, FetchRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FetchRecord {
+    fetched_at: u64,
+    main_branch: String,
+    commit: String,
+}
+
+fn log_path() -> String {
+    format!("{}/fetches.toml", conf::root_dir())
+}
+
+fn read_log() -> FetchLog {
+    match std::fs::read_to_string(log_path()) {
+        Ok(s) => toml::from_str(&s).unwrap_or_default(),
+        Err(_) => FetchLog::default(),
+    }
+}
+
+// Writes to a temporary file first, so that a command reading the log never sees half of it
+fn write_log(log: &FetchLog) {
+    let tmp = format!("{}.{}.tmp", log_path(), std::process::id());
+    let mut f = std::fs::File::create(&tmp).unwrap();
+    f.write_all(toml::to_string(log).unwrap().as_bytes())
+        .unwrap();
+    std::fs::rename(&tmp, log_path()).unwrap();
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Whether a fetch at `fetched_at` is still within the freshness window at time `now`
+fn is_fresh(fetched_at: u64, now: u64, freshness_secs: u64) -> bool {
+    fetched_at <= now && now - fetched_at < freshness_secs
+}
+
+pub fn repo_dir(repo_config: &conf::RepoConfig) -> String {
+    format!("{}/repos/{}", conf::root_dir(), repo_config.short_name())
+}
+
+// Fetches the main branch of the repo from origin, unless it was already fetched within the
+// configured freshness window. Pass `force` to always fetch.
+pub fn fetch_main(repo_config: &conf::RepoConfig, force: bool) -> Result<(), ()> {
+    let mut log = read_log();
+    if !force {
+        let freshness_secs = conf::get_config().fetch_freshness_secs;
+        if let Some(record) = log.repos.get(repo_config.short_name()) {
+            if record.main_branch == repo_config.main_branch
+                && is_fresh(record.fetched_at, now(), freshness_secs)
+            {
+                eprintln!(
+                    "{}: using `{}` as fetched {}s ago",
+                    repo_config.short_name(),
+                    repo_config.main_branch,
+                    now() - record.fetched_at
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    let dir = repo_dir(repo_config);
+    let refspec = format!(
+        "refs/heads/{}:refs/heads/{}",
+        &repo_config.main_branch, &repo_config.main_branch
+    );
+    remote::fetch(&dir, &[&refspec])?;
+
+    let (out, result) = cmd::system(
+        "git",
+        &["rev-parse", &repo_config.main_branch],
+        Some(&dir),
+        false,
+    );
+    if result.is_err() {
+        return Err(());
+    }
+
+    log.repos.insert(
+        repo_config.short_name().to_string(),
+        FetchRecord {
+            fetched_at: now(),
+            main_branch: repo_config.main_branch.clone(),
+            commit: out.trim().to_string(),
+        },
+    );
+    write_log(&log);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_fresh() {
+        assert!(is_fresh(100, 100, 60));
+        assert!(is_fresh(100, 159, 60));
+        assert!(!is_fresh(100, 160, 60));
+        assert!(!is_fresh(100, 100, 0));
+        // Clock went backwards, don't trust the record
+        assert!(!is_fresh(200, 100, 60));
+    }
+}
@@ -0,0 +1,505 @@
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cmd, conf, worktree};
+
+// How many operations to remember
+const MAX_ENTRIES: usize = 50;
+
+// A record of the state of every branch and repo that an operation is about to modify, so that
+// the operation can be undone
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub id: String,
+    pub command: String,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub branches: Vec<BranchState>,
+    // The configs of repos the operation changes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repos: Vec<conf::RepoConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BranchState {
+    // The commit the branch pointed to, if it existed
+    #[serde(default)]
+    pub head: String,
+    // A commit capturing everything in the worktree, if the worktree existed
+    #[serde(default)]
+    pub worktree: String,
+    // Whether the operation creates the worktree, so undoing it removes the worktree again
+    #[serde(default)]
+    pub created: bool,
+    // What the operation renamed the branch to, so that undoing it can rename it back
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub renamed_to: String,
+    pub config: conf::BranchConfig,
+}
+
+fn journal_dir() -> String {
+    format!("{}/journal", conf::root_dir())
+}
+
+fn repo_dir(repo: &str) -> String {
+    format!("{}/repos/{}", conf::root_dir(), repo)
+}
+
+fn branch_dir(name: &str) -> String {
+    format!("{}/branches/{}", conf::root_dir(), name)
+}
+
+// Journal commits are kept alive by refs, otherwise git would eventually garbage collect them
+fn journal_ref(id: &str, branch_name: &str) -> String {
+    format!("refs/g2/journal/{}/{}", id, branch_name)
+}
+
+// Where refs under `refs/g2/` that an operation deletes are kept until its entry expires, e.g.
+// `refs/g2/snapshots/x/1` becomes `refs/g2/set-aside/<id>/snapshots/x/1`
+fn set_aside_prefix(id: &str) -> String {
+    format!("refs/g2/set-aside/{}/", id)
+}
+
+// Lists the refs under `prefix` in the repo at `dir`
+fn list_refs(dir: &str, prefix: &str) -> Vec<String> {
+    let (out, res) = cmd::system(
+        "git",
+        &["for-each-ref", "--format=%(refname)", prefix],
+        Some(dir),
+        false,
+    );
+    if res.is_err() {
+        return Vec::new();
+    }
+    out.lines().map(|l| l.trim().to_string()).collect()
+}
+
+fn move_ref(dir: &str, from: &str, to: &str) -> Result<(), String> {
+    let (oid, res) = cmd::system("git", &["rev-parse", from], Some(dir), false);
+    if res.is_err() {
+        return Err(format!("couldn't read {}", from));
+    }
+    let (out, res) = cmd::system("git", &["update-ref", to, oid.trim()], Some(dir), false);
+    if res.is_err() {
+        return Err(format!("couldn't write {}: {}", to, out.trim()));
+    }
+    let _ = cmd::system("git", &["update-ref", "-d", from], Some(dir), false);
+    Ok(())
+}
+
+impl Entry {
+    pub fn new(command: &str) -> Self {
+        // Pruning once per operation, rather than whenever a branch is recorded, keeps journaling
+        // many branches cheap
+        prune();
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        Entry {
+            // Zero-padded so that entries sort by the time they were created
+            id: format!("{:020}", now.as_nanos()),
+            command: command.to_string(),
+            timestamp: now.as_secs(),
+            branches: Vec::new(),
+            repos: Vec::new(),
+        }
+    }
+
+    // Capture the current state of the branch and save it to the journal. This must happen
+    // before the branch is modified.
+    pub fn record(&mut self, branch_config: &conf::BranchConfig) {
+        if let Err(e) = self.try_record(branch_config) {
+            fail!("{}", e);
+        }
+    }
+
+    // Record a branch whose worktree the operation is about to create
+    pub fn record_created(&mut self, branch_config: &conf::BranchConfig) {
+        self.record(branch_config);
+        if let Some(state) = self.branches.last_mut() {
+            state.created = true;
+        }
+        self.save();
+    }
+
+    // Record the config of a repo before the operation changes it
+    pub fn record_repo(&mut self, repo_config: &conf::RepoConfig) {
+        self.repos.push(repo_config.clone());
+        self.save();
+    }
+
+    // Note that the operation renamed a recorded branch
+    pub fn record_rename(&mut self, branch_config: &conf::BranchConfig, renamed_to: &str) {
+        if let Some(state) = self
+            .branches
+            .iter_mut()
+            .find(|s| s.config.name == branch_config.name)
+        {
+            state.renamed_to = renamed_to.to_string();
+        }
+        self.save();
+    }
+
+    // Deletes refs under `refs/g2/` in the repo at `dir`, but only once the entry expires, so
+    // undoing the operation brings them back
+    pub fn set_aside_refs(&self, dir: &str, refs: &[String]) {
+        for refname in refs {
+            if let Some(rest) = refname.strip_prefix("refs/g2/") {
+                let to = format!("{}{}", set_aside_prefix(&self.id), rest);
+                if let Err(e) = move_ref(dir, refname, &to) {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
+
+    // Like `record`, but leaves it to the caller to decide what to do if the state of the branch
+    // can't be saved
+    pub fn try_record(&mut self, branch_config: &conf::BranchConfig) -> Result<(), String> {
+        let workdir = branch_dir(&branch_config.name);
+        let (repo, worktree_exists) = if std::path::Path::new(&workdir).exists() {
+            (git2::Repository::open(&workdir), true)
+        } else {
+            (
+                git2::Repository::open_bare(repo_dir(&branch_config.repo)),
+                false,
+            )
+        };
+        let repo = match repo {
+            Ok(r) => r,
+            Err(_) => {
+                return Err(format!(
+                    "couldn't open the repository for {}!",
+                    branch_config.name
+                ))
+            }
+        };
+
+        let head = repo
+            .refname_to_id(&format!("refs/heads/{}", branch_config.branch_name))
+            .map(|oid| oid.to_string())
+            .unwrap_or_default();
+
+        let mut worktree = String::new();
+        if worktree_exists {
+            let oid = match worktree::commit(&repo, &format!("g2 {}", self.command)) {
+                Ok(oid) => oid,
+                Err(e) => {
+                    return Err(format!(
+                        "couldn't save the state of {}: {}",
+                        branch_config.name,
+                        e.message()
+                    ))
+                }
+            };
+            if let Err(e) = repo.reference(
+                &journal_ref(&self.id, &branch_config.branch_name),
+                oid,
+                true,
+                "g2: journal",
+            ) {
+                return Err(format!(
+                    "couldn't save the state of {}: {}",
+                    branch_config.name,
+                    e.message()
+                ));
+            }
+            worktree = oid.to_string();
+        }
+
+        self.branches.push(BranchState {
+            head,
+            worktree,
+            created: false,
+            renamed_to: String::new(),
+            config: branch_config.clone(),
+        });
+        self.save();
+        Ok(())
+    }
+
+    fn path(&self) -> String {
+        format!("{}/{}.toml", journal_dir(), self.id)
+    }
+
+    fn save(&self) {
+        std::fs::create_dir_all(journal_dir()).unwrap();
+        let mut f = std::fs::File::create(self.path()).unwrap();
+        f.write_all(toml::to_string(self).unwrap().as_bytes())
+            .unwrap();
+    }
+
+    // Remove the entry from the journal, along with the refs keeping its commits alive and any
+    // refs it set aside
+    pub fn discard(&self) {
+        for state in &self.branches {
+            let dir = repo_dir(&state.config.repo);
+            let mut refs = list_refs(&dir, &set_aside_prefix(&self.id));
+            refs.push(journal_ref(&self.id, &state.config.branch_name));
+            for refname in refs {
+                let _ = cmd::system("git", &["update-ref", "-d", &refname], Some(&dir), false);
+            }
+        }
+        std::fs::remove_file(self.path()).ok();
+    }
+}
+
+fn entry_ids() -> Vec<String> {
+    let mut ids: Vec<_> = match std::fs::read_dir(journal_dir()) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+            .filter_map(|name| name.strip_suffix(".toml").map(|s| s.to_string()))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    ids.sort();
+    ids
+}
+
+fn read_entry(id: &str) -> Option<Entry> {
+    let contents = std::fs::read_to_string(format!("{}/{}.toml", journal_dir(), id)).ok()?;
+    toml::from_str(&contents).ok()
+}
+
+fn prune() {
+    let ids = entry_ids();
+    if ids.len() > MAX_ENTRIES {
+        for id in &ids[..ids.len() - MAX_ENTRIES] {
+            if let Some(entry) = read_entry(id) {
+                entry.discard();
+            }
+        }
+    }
+    prune_undone();
+}
+
+// Undoing saves what it overwrote under `refs/g2/undone/<id>/<branch>`. The entries are gone by
+// then, so those refs are pruned separately, keeping the most recent `MAX_ENTRIES` undos.
+fn prune_undone() {
+    for repo_config in &conf::get_config().repos {
+        let dir = repo_dir(repo_config.short_name());
+        let (out, res) = cmd::system(
+            "git",
+            &["for-each-ref", "--format=%(refname)", "refs/g2/undone/"],
+            Some(&dir),
+            false,
+        );
+        if res.is_err() {
+            continue;
+        }
+
+        let undone_id = |r: &str| {
+            r.strip_prefix("refs/g2/undone/")
+                .and_then(|r| r.split('/').next())
+                .map(|id| id.to_string())
+        };
+        let mut ids: Vec<String> = out.lines().filter_map(undone_id).collect();
+        ids.sort();
+        ids.dedup();
+        if ids.len() <= MAX_ENTRIES {
+            continue;
+        }
+
+        let stale = &ids[..ids.len() - MAX_ENTRIES];
+        for refname in out.lines() {
+            if undone_id(refname).map(|id| stale.contains(&id)) == Some(true) {
+                let _ = cmd::system("git", &["update-ref", "-d", refname], Some(&dir), false);
+            }
+        }
+    }
+}
+
+pub fn latest() -> Option<Entry> {
+    entry_ids().last().and_then(|id| read_entry(id))
+}
+
+// Save whatever is in the worktree now, in case the undo itself was a mistake
+fn save_undone(id: &str, state: &BranchState) -> Result<git2::Repository, String> {
+    let workdir = branch_dir(&state.config.name);
+    let repo = git2::Repository::open(&workdir).map_err(|e| e.message().to_string())?;
+    let current = worktree::commit(&repo, "g2 undo").map_err(|e| e.message().to_string())?;
+    repo.reference(
+        &format!("refs/g2/undone/{}/{}", id, state.config.branch_name),
+        current,
+        true,
+        "g2: undo",
+    )
+    .map_err(|e| e.message().to_string())?;
+    println!(
+        "the previous state of {} was saved as {}",
+        state.config.name, current
+    );
+    Ok(repo)
+}
+
+// Undo creating a worktree: remove it, then put the branch back where it was, or delete it if it
+// didn't exist
+fn remove_created(id: &str, state: &BranchState) -> Result<(), String> {
+    let repo_dir = repo_dir(&state.config.repo);
+    let workdir = branch_dir(&state.config.name);
+    let branch_name = &state.config.branch_name;
+
+    if std::path::Path::new(&workdir).exists() {
+        save_undone(id, state)?;
+        let (out, res) = cmd::system(
+            "git",
+            &["worktree", "remove", "--force", &workdir],
+            Some(&repo_dir),
+            false,
+        );
+        if res.is_err() {
+            return Err(format!("couldn't remove the worktree: {}", out.trim()));
+        }
+    }
+
+    let (_, res) = if state.head.is_empty() {
+        cmd::system(
+            "git",
+            &["branch", "-D", branch_name],
+            Some(&repo_dir),
+            false,
+        )
+    } else {
+        cmd::system(
+            "git",
+            &["branch", "-f", branch_name, &state.head],
+            Some(&repo_dir),
+            false,
+        )
+    };
+    if res.is_err() {
+        return Err(format!("couldn't restore branch {}", branch_name));
+    }
+    Ok(())
+}
+
+// Put a repo's config back the way it was recorded, keeping the bare repo's HEAD on its main
+// branch
+pub fn restore_repo(repo_config: &conf::RepoConfig) -> Result<(), String> {
+    let repo = git2::Repository::open_bare(repo_dir(repo_config.short_name()))
+        .map_err(|e| e.message().to_string())?;
+    repo.set_head(&format!("refs/heads/{}", repo_config.main_branch))
+        .map_err(|e| e.message().to_string())
+}
+
+// Put a branch back the way it was recorded: recreate the branch and worktree if they were
+// removed, reset the branch, then bring back the worktree contents
+pub fn restore(id: &str, state: &BranchState) -> Result<(), String> {
+    let repo_dir = repo_dir(&state.config.repo);
+    let workdir = branch_dir(&state.config.name);
+    let branch_name = &state.config.branch_name;
+
+    for refname in list_refs(&repo_dir, &set_aside_prefix(id)) {
+        let original = format!("refs/g2/{}", &refname[set_aside_prefix(id).len()..]);
+        move_ref(&repo_dir, &refname, &original)?;
+    }
+
+    if state.created {
+        return remove_created(id, state);
+    }
+
+    if !std::path::Path::new(&workdir).exists() {
+        if state.worktree.is_empty() {
+            // There was no worktree before either
+            return Ok(());
+        }
+
+        // The worktree was deleted, so git's record of it is stale
+        let _ = cmd::system("git", &["worktree", "prune"], Some(&repo_dir), false);
+
+        if !state.renamed_to.is_empty() {
+            let _ = cmd::system(
+                "git",
+                &["branch", "-M", &state.renamed_to, branch_name],
+                Some(&repo_dir),
+                false,
+            );
+        }
+
+        let (_, res) = cmd::system(
+            "git",
+            &["branch", "-f", branch_name, &state.head],
+            Some(&repo_dir),
+            false,
+        );
+        if res.is_err() {
+            return Err(format!("couldn't recreate branch {}", branch_name));
+        }
+
+        let (out, res) = cmd::system(
+            "git",
+            &["worktree", "add", &workdir, branch_name],
+            Some(&repo_dir),
+            false,
+        );
+        if res.is_err() {
+            return Err(format!("couldn't recreate worktree: {}", out.trim()));
+        }
+    } else {
+        let repo = save_undone(id, state)?;
+
+        match repo.state() {
+            git2::RepositoryState::Merge => {
+                let _ = cmd::system("git", &["merge", "--abort"], Some(&workdir), false);
+            }
+            git2::RepositoryState::Clean => (),
+            _ => {
+                let _ = cmd::system("git", &["rebase", "--abort"], Some(&workdir), false);
+            }
+        }
+
+        if !state.head.is_empty() {
+            let (_, res) = cmd::system(
+                "git",
+                &["reset", "-q", "--hard", &state.head],
+                Some(&workdir),
+                false,
+            );
+            if res.is_err() {
+                return Err(format!("couldn't reset {}", branch_name));
+            }
+        }
+
+        // Changes stashed by an unfinished sync are part of the worktree restored below
+        let stash = format!("refs/g2/stashes/{}", branch_name);
+        let _ = cmd::system("git", &["update-ref", "-d", &stash], Some(&workdir), false);
+    }
+
+    if state.worktree.is_empty() {
+        return Ok(());
+    }
+
+    let (_, res) = cmd::system(
+        "git",
+        &["checkout", &state.worktree, "--", "."],
+        Some(&workdir),
+        false,
+    );
+    if res.is_err() {
+        return Err(String::from("couldn't restore the worktree"));
+    }
+
+    // Files which were deleted in the worktree at the time
+    let (out, _) = cmd::system(
+        "git",
+        &[
+            "diff",
+            "--name-only",
+            "--diff-filter=D",
+            &state.head,
+            &state.worktree,
+        ],
+        Some(&workdir),
+        false,
+    );
+    for deleted in out.lines().map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        std::fs::remove_file(format!("{}/{}", workdir, deleted)).ok();
+    }
+
+    // Leave the changes unstaged, as they were
+    let _ = cmd::system("git", &["reset", "-q"], Some(&workdir), false);
+    Ok(())
+}
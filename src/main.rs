@@ -4,7 +4,15 @@ mod fail;
 mod actions;
 mod cmd;
 mod conf;
+mod fetch;
+mod journal;
+mod markers;
+mod remote;
+mod secret;
+mod status;
 mod tui;
+mod watch;
+mod worktree;
 
 fn main() {
     let args: Vec<_> = std::env::args().collect();
@@ -13,16 +21,23 @@ fn main() {
     }
     match args[1].as_str() {
         "clone" => actions::clone(args[2].as_str()),
+        "repo" => actions::repo(&args[2..]),
         "branch" => actions::branch(&args[2..]),
+        "branches" => actions::branches(&args[2..]),
         "diff" => actions::diff(&args[2..]),
-        "files" => actions::files(),
-        "sync" => actions::sync(),
-        "upload" => actions::upload(),
+        "files" => actions::files(&args[2..]),
+        "sync" => actions::sync(&args[2..]),
+        "fetch" => actions::fetch(&args[2..]),
+        "upload" => actions::upload(&args[2..]),
         "auto" => actions::auto(),
         "clean" => actions::clean(),
         "new" => actions::new(&args[2..]),
-        "status" => actions::status(),
+        "status" => actions::status(&args[2..]),
         "revert" => actions::revert(&args[2..]),
+        "snapshots" => actions::snapshots(&args[2..]),
+        "restore" => actions::restore(&args[2..]),
+        "undo" => actions::undo(&args[2..]),
+        "watch" => actions::watch(&args[2..]),
         "check" => actions::check(),
         "adopt" => actions::adopt(&args[2..]),
         _ => fail!("command `{}` not found", args[1]),
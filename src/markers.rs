@@ -0,0 +1,145 @@
+// The length of the conflict markers that git writes by default
+const MARKER_SIZE: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MarkerKind {
+    Ours,
+    Base,
+    Separator,
+    Theirs,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Marker {
+    // 1-indexed line number of the marker
+    pub line: usize,
+    pub text: String,
+}
+
+// Git treats a file as binary if there's a NUL byte within the first 8000 bytes
+pub fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(8000).any(|b| *b == 0)
+}
+
+fn marker_kind(line: &str) -> Option<MarkerKind> {
+    let bytes = line.as_bytes();
+    let kind = match bytes.first()? {
+        b'<' => MarkerKind::Ours,
+        b'|' => MarkerKind::Base,
+        b'=' => MarkerKind::Separator,
+        b'>' => MarkerKind::Theirs,
+        _ => return None,
+    };
+
+    if bytes.len() < MARKER_SIZE || bytes[..MARKER_SIZE].iter().any(|b| *b != bytes[0]) {
+        return None;
+    }
+
+    // The separator stands on its own line, the other markers may be followed by a label
+    match (kind, bytes.get(MARKER_SIZE)) {
+        (_, None) => Some(kind),
+        (MarkerKind::Separator, Some(_)) => None,
+        (_, Some(b' ')) => Some(kind),
+        _ => None,
+    }
+}
+
+// Finds all of the conflict markers in a file. Binary files never contain markers. A `=======`
+// line is only reported when it's part of a conflict, since it's also a common way to underline
+// headings.
+pub fn find_markers(contents: &[u8]) -> Vec<Marker> {
+    if is_binary(contents) {
+        return Vec::new();
+    }
+
+    let contents = String::from_utf8_lossy(contents);
+    let found: Vec<(usize, MarkerKind, &str)> = contents
+        .lines()
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            let line = line.trim_end_matches('\r');
+            marker_kind(line).map(|kind| (idx + 1, kind, line))
+        })
+        .collect();
+
+    let mut output = Vec::new();
+    for (idx, (line, kind, text)) in found.iter().enumerate() {
+        if *kind == MarkerKind::Separator {
+            let opened = found[..idx]
+                .iter()
+                .rev()
+                .map(|(_, k, _)| *k)
+                .find(|k| *k != MarkerKind::Separator)
+                .map(|k| k == MarkerKind::Ours || k == MarkerKind::Base)
+                .unwrap_or(false);
+            let closed = found[idx + 1..]
+                .iter()
+                .map(|(_, k, _)| *k)
+                .find(|k| *k != MarkerKind::Separator)
+                .map(|k| k == MarkerKind::Theirs)
+                .unwrap_or(false);
+            if !opened && !closed {
+                continue;
+            }
+        }
+
+        output.push(Marker {
+            line: *line,
+            text: text.to_string(),
+        });
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(contents: &str) -> Vec<usize> {
+        find_markers(contents.as_bytes())
+            .into_iter()
+            .map(|m| m.line)
+            .collect()
+    }
+
+    #[test]
+    fn test_find_markers() {
+        let contents = "\
+fn main() {
+<<<<<<< HEAD
+    println!(\"ours\");
+||||||| merged common ancestors
+    println!(\"base\");
+=======
+    println!(\"theirs\");
+>>>>>>> main
+}
+";
+        assert_eq!(lines(contents), vec![2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn test_partially_resolved_markers() {
+        assert_eq!(lines("ours\n=======\ntheirs\n>>>>>>> main\n"), vec![2, 4]);
+    }
+
+    #[test]
+    fn test_ignores_non_markers() {
+        let contents = "\
+Heading
+=======
+
+    x <<<<<<< y
+<<<<<<<< eight
+>>>>>>>no-space
+========
+";
+        assert_eq!(lines(contents), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_ignores_binary_files() {
+        assert_eq!(find_markers(b"\0<<<<<<< HEAD\n"), Vec::new());
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::IsTerminal;
+
+use crate::cmd;
+
+pub fn tracking_ref(branch: &str) -> String {
+    format!("refs/remotes/origin/{}", branch)
+}
+
+fn show_progress(label: &str, current: usize, total: usize) {
+    if total > 0 && std::io::stderr().is_terminal() {
+        eprint!("\r{}: {}/{}", label, current, total);
+        if current == total {
+            eprintln!();
+        }
+    }
+}
+
+// Remote callbacks which authenticate the same way git would: first the ssh-agent, then the
+// usual SSH keys, then any configured git credential helper. Each method is only tried once,
+// since libgit2 keeps asking for credentials until it gets working ones.
+fn callbacks<'a>() -> git2::RemoteCallbacks<'a> {
+    let mut tried_agent = false;
+    let mut tried_keys = 0;
+    let mut tried_helper = false;
+    let mut tried_default = false;
+
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |url, username_from_url, allowed| {
+        let username = username_from_url.unwrap_or("git");
+        if allowed.contains(git2::CredentialType::USERNAME) {
+            return git2::Cred::username(username);
+        }
+
+        if allowed.contains(git2::CredentialType::SSH_KEY) {
+            if !tried_agent {
+                tried_agent = true;
+                return git2::Cred::ssh_key_from_agent(username);
+            }
+
+            let home = std::env::var("HOME").unwrap_or_default();
+            let keys = ["id_ed25519", "id_ecdsa", "id_rsa"];
+            while let Some(key) = keys.get(tried_keys) {
+                tried_keys += 1;
+                let path = std::path::PathBuf::from(format!("{}/.ssh/{}", home, key));
+                if path.exists() {
+                    return git2::Cred::ssh_key(username, None, &path, None);
+                }
+            }
+        }
+
+        if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) && !tried_helper {
+            tried_helper = true;
+            let config = git2::Config::open_default()?;
+            return git2::Cred::credential_helper(&config, url, username_from_url);
+        }
+
+        if allowed.contains(git2::CredentialType::DEFAULT) && !tried_default {
+            tried_default = true;
+            return git2::Cred::default();
+        }
+
+        Err(git2::Error::from_str(
+            "couldn't authenticate with ssh-agent, ssh keys or a git credential helper",
+        ))
+    });
+
+    callbacks.transfer_progress(|progress| {
+        show_progress(
+            "receiving objects",
+            progress.received_objects(),
+            progress.total_objects(),
+        );
+        true
+    });
+
+    callbacks.push_transfer_progress(|current, total, _| {
+        show_progress("writing objects", current, total);
+    });
+
+    callbacks.push_update_reference(|refname, status| match status {
+        Some(msg) => Err(git2::Error::new(
+            git2::ErrorCode::NotFastForward,
+            git2::ErrorClass::Reference,
+            format!("the remote rejected {}: {}", refname, msg),
+        )),
+        None => Ok(()),
+    });
+
+    callbacks
+}
+
+pub fn fetch_in_process(repo: &git2::Repository, refspecs: &[&str]) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let mut opts = git2::FetchOptions::new();
+    opts.remote_callbacks(callbacks());
+    remote.fetch(refspecs, Some(&mut opts), None)
+}
+
+// Bare clones have no fetch refspec, so branches which were fetched or pushed without going through
+// `record_push` have no remote-tracking ref. Fetches it if the branch exists on origin.
+pub fn seed_tracking_ref(repo: &git2::Repository, branch: &str) -> Result<(), git2::Error> {
+    if repo.refname_to_id(&tracking_ref(branch)).is_ok() {
+        return Ok(());
+    }
+
+    let remote_ref = format!("refs/heads/{}", branch);
+    let exists = {
+        let mut remote = repo.find_remote("origin")?;
+        let connection = remote.connect_auth(git2::Direction::Fetch, Some(callbacks()), None)?;
+        let heads = connection.list()?;
+        heads.iter().any(|head| head.name() == remote_ref)
+    };
+    if !exists {
+        return Ok(());
+    }
+
+    let refspec = format!("+{}:{}", remote_ref, tracking_ref(branch));
+    fetch_in_process(repo, &[refspec.as_str()])
+}
+
+// Whether pushing the branch would overwrite commits on origin, e.g. because it was rebased since
+// it was last pushed
+pub fn rewrites_history(repo: &git2::Repository, branch: &str) -> bool {
+    let pushed = match repo.refname_to_id(&tracking_ref(branch)) {
+        Ok(oid) => oid,
+        Err(_) => return false,
+    };
+    let head = match repo.refname_to_id(&format!("refs/heads/{}", branch)) {
+        Ok(oid) => oid,
+        Err(_) => return false,
+    };
+    head != pushed && !repo.graph_descendant_of(head, pushed).unwrap_or(false)
+}
+
+// Pushes a local branch to the branch of the same name on origin. With `force_with_lease`, the
+// remote branch is overwritten, but only if it hasn't moved since we last pushed it.
+pub fn push_in_process(
+    repo: &git2::Repository,
+    branch: &str,
+    force_with_lease: bool,
+) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let remote_ref = format!("refs/heads/{}", branch);
+
+    if force_with_lease {
+        seed_tracking_ref(repo, branch)?;
+        let expected = repo.refname_to_id(&tracking_ref(branch)).ok();
+        let actual = {
+            let connection = remote.connect_auth(git2::Direction::Push, Some(callbacks()), None)?;
+            let heads = connection.list()?;
+            heads
+                .iter()
+                .find(|head| head.name() == remote_ref)
+                .map(|head| head.oid())
+        };
+
+        if actual.is_some() && actual != expected {
+            return Err(git2::Error::new(
+                git2::ErrorCode::NotFastForward,
+                git2::ErrorClass::Reference,
+                format!(
+                    "the remote branch `{}` changed since it was last pushed from here! if you're \
+                     sure it's safe to overwrite, run `git fetch origin {}:{}` and try again",
+                    branch,
+                    remote_ref,
+                    tracking_ref(branch)
+                ),
+            ));
+        }
+    }
+
+    let refspec = format!(
+        "{}refs/heads/{}:{}",
+        if force_with_lease { "+" } else { "" },
+        branch,
+        remote_ref
+    );
+    let mut opts = git2::PushOptions::new();
+    opts.remote_callbacks(callbacks());
+    remote.push(&[refspec.as_str()], Some(&mut opts))?;
+
+    record_push(repo, branch)
+}
+
+// Bare clones have no fetch refspec, so nothing updates the remote-tracking ref or upstream
+// config for us after a push. Record them ourselves, like `git push --set-upstream` would.
+fn record_push(repo: &git2::Repository, branch: &str) -> Result<(), git2::Error> {
+    let oid = repo.refname_to_id(&format!("refs/heads/{}", branch))?;
+    repo.reference(&tracking_ref(branch), oid, true, "g2: push")?;
+
+    let mut config = repo.config()?;
+    config.set_str(&format!("branch.{}.remote", branch), "origin")?;
+    config.set_str(
+        &format!("branch.{}.merge", branch),
+        &format!("refs/heads/{}", branch),
+    )?;
+    Ok(())
+}
+
+// Asks origin which branch its HEAD points to
+pub fn default_branch_in_process(repo: &git2::Repository) -> Result<String, git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let connection = remote.connect_auth(git2::Direction::Fetch, Some(callbacks()), None)?;
+    let head = connection.default_branch()?;
+    match head.as_str() {
+        Some(name) => Ok(name.trim_start_matches("refs/heads/").to_string()),
+        None => Err(git2::Error::from_str("the remote HEAD isn't valid utf-8")),
+    }
+}
+
+// Parses the output of `git ls-remote --symref origin HEAD`, which looks like:
+//
+//   ref: refs/heads/main	HEAD
+//   0123456789abcdef0123456789abcdef01234567	HEAD
+fn parse_symref(output: &str) -> Option<String> {
+    let line = output.lines().find(|l| l.starts_with("ref: "))?;
+    let target = line["ref: ".len()..].split_whitespace().next()?;
+    Some(target.trim_start_matches("refs/heads/").to_string())
+}
+
+// Asks origin for its default branch using libgit2, falling back to the git CLI
+pub fn default_branch(workdir: &str) -> Result<String, ()> {
+    let repo = match git2::Repository::discover(workdir) {
+        Ok(r) => r,
+        Err(_) => return Err(()),
+    };
+
+    match default_branch_in_process(&repo) {
+        Ok(name) => return Ok(name),
+        Err(e) => eprintln!("libgit2 failed ({}), trying again with git...", e.message()),
+    }
+
+    let (out, result) = cmd::system(
+        "git",
+        &["ls-remote", "--symref", "origin", "HEAD"],
+        Some(workdir),
+        false,
+    );
+    if result.is_err() {
+        return Err(());
+    }
+    parse_symref(&out).ok_or(())
+}
+
+// Fetch from origin using libgit2, falling back to the git CLI if that doesn't work
+pub fn fetch(workdir: &str, refspecs: &[&str]) -> Result<(), ()> {
+    let repo = match git2::Repository::discover(workdir) {
+        Ok(r) => r,
+        Err(_) => return Err(()),
+    };
+
+    match fetch_in_process(&repo, refspecs) {
+        Ok(_) => return Ok(()),
+        Err(e) => eprintln!("libgit2 failed ({}), trying again with git...", e.message()),
+    }
+
+    let mut args = vec!["fetch", "-q", "origin"];
+    args.extend(refspecs);
+    match cmd::system("git", &args, Some(workdir), true) {
+        (_, Ok(_)) => Ok(()),
+        (_, Err(_)) => Err(()),
+    }
+}
+
+// Push the branch to origin using libgit2, falling back to the git CLI if that doesn't work
+pub fn push(workdir: &str, branch: &str, force_with_lease: bool) -> Result<(), ()> {
+    let repo = match git2::Repository::discover(workdir) {
+        Ok(r) => r,
+        Err(_) => return Err(()),
+    };
+
+    match push_in_process(&repo, branch, force_with_lease) {
+        Ok(_) => return Ok(()),
+        // The CLI won't do any better if the push was rejected
+        Err(e) if e.code() == git2::ErrorCode::NotFastForward => {
+            eprintln!("{}", e.message());
+            return Err(());
+        }
+        Err(e) => eprintln!("libgit2 failed ({}), trying again with git...", e.message()),
+    }
+
+    let refspec = format!("refs/heads/{}:refs/heads/{}", branch, branch);
+    let lease = format!(
+        "--force-with-lease={}:{}",
+        branch,
+        repo.refname_to_id(&tracking_ref(branch))
+            .map(|oid| oid.to_string())
+            .unwrap_or_default()
+    );
+    let mut args = vec!["push", "--set-upstream"];
+    if force_with_lease {
+        args.push(&lease);
+    }
+    args.extend(&["origin", &refspec]);
+
+    let (_, result) = cmd::system("git", &args, Some(workdir), true);
+    if result.is_err() {
+        return Err(());
+    }
+
+    record_push(&repo, branch).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_file(repo: &git2::Repository, name: &str, contents: &str) -> git2::Oid {
+        let blob = repo.blob(contents.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert(name, blob, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        let sig = git2::Signature::now("g2", "g2@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, contents, &tree, &parents)
+            .unwrap()
+    }
+
+    fn setup() -> (tempfile::TempDir, git2::Repository, git2::Repository) {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = git2::Repository::init_bare(dir.path().join("upstream")).unwrap();
+        upstream.set_head("refs/heads/main").unwrap();
+        commit_file(&upstream, "README.md", "hello");
+
+        let local = git2::Repository::init_bare(dir.path().join("local")).unwrap();
+        let url = format!("file://{}", dir.path().join("upstream").display());
+        // Like `git clone --bare`, set up origin without a fetch refspec
+        local
+            .config()
+            .unwrap()
+            .set_str("remote.origin.url", &url)
+            .unwrap();
+        (dir, upstream, local)
+    }
+
+    #[test]
+    fn test_default_branch() {
+        let (_dir, upstream, local) = setup();
+        assert_eq!(default_branch_in_process(&local).unwrap(), "main");
+
+        upstream.set_head("refs/heads/trunk").unwrap();
+        upstream
+            .reference(
+                "refs/heads/trunk",
+                upstream.refname_to_id("refs/heads/main").unwrap(),
+                false,
+                "",
+            )
+            .unwrap();
+        assert_eq!(default_branch_in_process(&local).unwrap(), "trunk");
+    }
+
+    #[test]
+    fn test_parse_symref() {
+        let output =
+            "ref: refs/heads/develop\tHEAD\n0123456789abcdef0123456789abcdef01234567\tHEAD\n";
+        assert_eq!(parse_symref(output), Some(String::from("develop")));
+        assert_eq!(parse_symref(""), None);
+    }
+
+    #[test]
+    fn test_fetch_file_remote() {
+        let (_dir, upstream, local) = setup();
+        fetch_in_process(&local, &["refs/heads/main:refs/heads/main"]).unwrap();
+        assert_eq!(
+            local.refname_to_id("refs/heads/main").unwrap(),
+            upstream.refname_to_id("refs/heads/main").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_push_file_remote() {
+        let (_dir, upstream, local) = setup();
+        fetch_in_process(&local, &["refs/heads/main:refs/heads/main"]).unwrap();
+        local.set_head("refs/heads/main").unwrap();
+        let pushed = commit_file(&local, "README.md", "goodbye");
+
+        push_in_process(&local, "main", false).unwrap();
+        assert_eq!(upstream.refname_to_id("refs/heads/main").unwrap(), pushed);
+        assert_eq!(local.refname_to_id(&tracking_ref("main")).unwrap(), pushed);
+    }
+
+    #[test]
+    fn test_force_with_lease() {
+        let (_dir, upstream, local) = setup();
+        fetch_in_process(&local, &["refs/heads/main:refs/heads/main"]).unwrap();
+        local.set_head("refs/heads/main").unwrap();
+
+        // Never pushed from here, so the remote-tracking ref is fetched before comparing
+        let pushed = commit_file(&local, "README.md", "rewritten");
+        push_in_process(&local, "main", true).unwrap();
+        assert_eq!(upstream.refname_to_id("refs/heads/main").unwrap(), pushed);
+
+        // Someone else pushed since, so their work isn't overwritten
+        upstream.set_head("refs/heads/main").unwrap();
+        commit_file(&upstream, "README.md", "theirs");
+        commit_file(&local, "README.md", "ours");
+        let err = push_in_process(&local, "main", true).unwrap_err();
+        assert_eq!(err.code(), git2::ErrorCode::NotFastForward);
+    }
+
+    #[test]
+    fn test_rewrites_history() {
+        let (_dir, _upstream, local) = setup();
+        fetch_in_process(&local, &["refs/heads/main:refs/heads/main"]).unwrap();
+        local.set_head("refs/heads/main").unwrap();
+        seed_tracking_ref(&local, "main").unwrap();
+        assert!(!rewrites_history(&local, "main"));
+
+        commit_file(&local, "README.md", "appended");
+        assert!(!rewrites_history(&local, "main"));
+
+        // Like a rebase, replace the branch with a commit that doesn't contain the pushed one
+        let tree = local.head().unwrap().peel_to_tree().unwrap();
+        let sig = git2::Signature::now("g2", "g2@example.com").unwrap();
+        let rewritten = local
+            .commit(None, &sig, &sig, "rewritten", &tree, &[])
+            .unwrap();
+        local
+            .reference("refs/heads/main", rewritten, true, "")
+            .unwrap();
+        assert!(rewrites_history(&local, "main"));
+    }
+}
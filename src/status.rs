@@ -0,0 +1,466 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{cmd, remote};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    Untracked,
+    Renamed,
+    Copied,
+    // Only the file mode changed, e.g. it was made executable
+    #[serde(rename = "mode")]
+    ModeChanged,
+    // A file became a symlink, or the other way around
+    TypeChange,
+}
+
+// How a file differs from the merge base of the branch
+#[derive(Debug, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    // Where a renamed or copied file came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub kind: ChangeKind,
+    pub added: usize,
+    pub removed: usize,
+    pub binary: bool,
+    // The old and new file modes, if they're different
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_mode: Option<String>,
+}
+
+fn line_counts(added: usize, removed: usize) -> Option<String> {
+    match (added, removed) {
+        (0, 0) => None,
+        (x, 0) => Some(format!("+{}", x)),
+        (0, x) => Some(format!("-{}", x)),
+        (x, y) => Some(format!("+{}, -{}", x, y)),
+    }
+}
+
+// Modes are shown like `chmod` would take them, e.g. `755`
+fn short_mode(mode: &Option<String>) -> &str {
+    match mode {
+        Some(m) if m.len() > 3 => &m[m.len() - 3..],
+        Some(m) => m,
+        None => "?",
+    }
+}
+
+impl FileChange {
+    // The summary shown by `g2 status`, e.g. `[+16, -6]`
+    pub fn summary(&self) -> String {
+        let counts = line_counts(self.added, self.removed);
+        match self.kind {
+            ChangeKind::Deleted => String::from("[deleted]"),
+            ChangeKind::Untracked => String::from("[new]"),
+            ChangeKind::TypeChange => String::from("[typechange]"),
+            ChangeKind::ModeChanged => format!(
+                "[mode {} -> {}]",
+                short_mode(&self.old_mode),
+                short_mode(&self.new_mode)
+            ),
+            ChangeKind::Renamed | ChangeKind::Copied => {
+                let label = if self.kind == ChangeKind::Renamed {
+                    "renamed"
+                } else {
+                    "copied"
+                };
+                match counts {
+                    Some(c) if !self.binary => format!("[{}, {}]", label, c),
+                    _ => format!("[{}]", label),
+                }
+            }
+            ChangeKind::Modified if self.binary => String::from("[binary]"),
+            ChangeKind::Added | ChangeKind::Modified => match counts {
+                Some(c) => format!("[{}]", c),
+                None => String::from("[new]"),
+            },
+        }
+    }
+
+    // The path shown by `g2 status`, including where renamed and copied files came from
+    pub fn display_path(&self) -> String {
+        match &self.old_path {
+            Some(old) => format!("{} -> {}", old, self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PullRequest {
+    pub number: u64,
+    pub title: String,
+    pub url: String,
+    pub state: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Upstream {
+    pub name: String,
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+// Where the branch stands relative to main, and to what was last uploaded
+#[derive(Debug, Serialize)]
+pub struct SyncState {
+    pub main: String,
+    pub ahead_main: usize,
+    pub behind_main: usize,
+    // Not set if the branch was never uploaded
+    pub upstream: Option<Upstream>,
+    pub needs_sync: bool,
+    pub needs_upload: bool,
+}
+
+impl SyncState {
+    // The header shown by `g2 status`, e.g. `2 ahead, 1 behind main | needs sync`
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format!(
+            "{} ahead, {} behind {}",
+            self.ahead_main, self.behind_main, self.main
+        )];
+        match &self.upstream {
+            Some(u) => parts.push(format!("{} ahead, {} behind {}", u.ahead, u.behind, u.name)),
+            None => parts.push(String::from("not uploaded")),
+        }
+
+        let mut hints = Vec::new();
+        if self.needs_sync {
+            hints.push("needs sync");
+        }
+        if self.needs_upload {
+            hints.push("needs upload");
+        }
+        if hints.is_empty() {
+            hints.push("up to date");
+        }
+        parts.push(hints.join(", "));
+
+        parts.join(" | ")
+    }
+}
+
+// A branch, as reported by `g2 status --json` and `g2 branches --json`
+#[derive(Debug, Serialize)]
+pub struct BranchStatus {
+    pub name: String,
+    pub branch: String,
+    pub repo: String,
+    pub pull_request: Option<PullRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileChange>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListedPullRequest {
+    head_ref_name: String,
+    #[serde(flatten)]
+    pull_request: PullRequest,
+}
+
+// Looks up the pull request for the branch checked out in the current directory
+pub fn pull_request() -> Option<PullRequest> {
+    let (out, res) = cmd::system(
+        "gh",
+        &["pr", "view", "--json", "number,title,url,state"],
+        None,
+        false,
+    );
+    if res.is_err() {
+        return None;
+    }
+    serde_json::from_str(&out).ok()
+}
+
+// Looks up the pull requests of every branch in the repository at `workdir`, by branch name
+pub fn pull_requests(workdir: &str) -> Vec<(String, PullRequest)> {
+    let (out, res) = cmd::system(
+        "gh",
+        &[
+            "pr",
+            "list",
+            "--state",
+            "all",
+            "--limit",
+            "200",
+            "--json",
+            "number,title,url,state,headRefName",
+        ],
+        Some(workdir),
+        false,
+    );
+    if res.is_err() {
+        return Vec::new();
+    }
+    let listed: Vec<ListedPullRequest> = serde_json::from_str(&out).unwrap_or_default();
+    listed
+        .into_iter()
+        .map(|l| (l.head_ref_name, l.pull_request))
+        .collect()
+}
+
+// Finds the commit that `branch` forked from `main` at
+pub fn merge_base(
+    repo: &git2::Repository,
+    branch: &str,
+    main: &str,
+) -> Result<git2::Oid, git2::Error> {
+    let branch = repo.revparse_single(branch)?.peel_to_commit()?;
+    let main = repo.revparse_single(main)?.peel_to_commit()?;
+    repo.merge_base(branch.id(), main.id())
+}
+
+// Whether the worktree has anything in it that isn't in `tree`
+fn differs_from(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    ignored: &[String],
+) -> Result<bool, git2::Error> {
+    let mut opts = git2::DiffOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    let diff = repo.diff_tree_to_workdir_with_index(Some(tree), Some(&mut opts))?;
+    let differs = diff.deltas().any(|d| {
+        let ignored_file = d.status() == git2::Delta::Untracked
+            && d.new_file()
+                .path()
+                .and_then(|p| p.to_str())
+                .map(|p| ignored.iter().any(|i| i == p))
+                .unwrap_or(false);
+        !ignored_file
+    });
+    Ok(differs)
+}
+
+// Counts commits ahead of and behind main and the uploaded branch, as of the last fetch
+pub fn sync_state(
+    repo: &git2::Repository,
+    branch: &str,
+    main: &str,
+    ignored: &[String],
+) -> Result<SyncState, git2::Error> {
+    let head = repo.revparse_single(branch)?.peel_to_commit()?;
+    let main_commit = repo.revparse_single(main)?.peel_to_commit()?;
+    let (ahead_main, behind_main) = repo.graph_ahead_behind(head.id(), main_commit.id())?;
+
+    let upstream_name = format!("origin/{}", branch);
+    let (upstream, needs_upload) = match repo.refname_to_id(&remote::tracking_ref(branch)) {
+        Ok(pushed) => {
+            let (ahead, behind) = repo.graph_ahead_behind(head.id(), pushed)?;
+            let pushed_tree = repo.find_commit(pushed)?.tree()?;
+            let needs_upload = ahead > 0 || differs_from(repo, &pushed_tree, ignored)?;
+            let upstream = Upstream {
+                name: upstream_name,
+                ahead,
+                behind,
+            };
+            (Some(upstream), needs_upload)
+        }
+        Err(_) => {
+            let base = repo.find_commit(repo.merge_base(head.id(), main_commit.id())?)?;
+            let needs_upload = ahead_main > 0 || differs_from(repo, &base.tree()?, ignored)?;
+            (None, needs_upload)
+        }
+    };
+
+    Ok(SyncState {
+        main: main.to_string(),
+        ahead_main,
+        behind_main,
+        upstream,
+        needs_sync: behind_main > 0,
+        needs_upload,
+    })
+}
+
+// libgit2 doesn't always hash files in the worktree while diffing, so do it if needed
+fn worktree_id(repo: &git2::Repository, file: &git2::DiffFile) -> git2::Oid {
+    if !file.id().is_zero() {
+        return file.id();
+    }
+    match (repo.workdir(), file.path()) {
+        (Some(workdir), Some(path)) => {
+            git2::Oid::hash_file(git2::ObjectType::Blob, workdir.join(path)).unwrap_or(file.id())
+        }
+        _ => file.id(),
+    }
+}
+
+// Lists the changes in the worktree since `base`, including untracked files other than the
+// `ignored` ones, the same way `git diff <base>` and `git ls-files --others` would. Counting
+// lines and finding renames both mean comparing file contents, so they're only done if
+// `count_lines` and `find_renames` are set.
+pub fn file_changes(
+    repo: &git2::Repository,
+    base: git2::Oid,
+    ignored: &[String],
+    count_lines: bool,
+    find_renames: bool,
+) -> Result<Vec<FileChange>, git2::Error> {
+    let base_tree = repo.find_commit(base)?.tree()?;
+    let mut opts = git2::DiffOptions::new();
+    opts.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true)
+        .include_typechange(true);
+    let mut diff = repo.diff_tree_to_workdir_with_index(Some(&base_tree), Some(&mut opts))?;
+
+    // Files moved without `git mv` are untracked, so they need to be considered as well
+    if find_renames {
+        let mut find_opts = git2::DiffFindOptions::new();
+        find_opts.renames(true).copies(true).for_untracked(true);
+        diff.find_similar(Some(&mut find_opts))?;
+    }
+
+    let mut output = Vec::new();
+    for (idx, delta) in diff.deltas().enumerate() {
+        let old_path = delta.old_file().path().and_then(|p| p.to_str());
+        let new_path = delta.new_file().path().and_then(|p| p.to_str());
+        let (path, old_path) = match (delta.status(), old_path, new_path) {
+            (git2::Delta::Deleted, Some(p), _) => (p, None),
+            (git2::Delta::Renamed, Some(old), Some(p))
+            | (git2::Delta::Copied, Some(old), Some(p)) => (p, Some(old.to_string())),
+            (_, _, Some(p)) => (p, None),
+            _ => continue,
+        };
+
+        let (old_mode, new_mode) = (delta.old_file().mode(), delta.new_file().mode());
+        let mode_changed = delta.status() == git2::Delta::Modified
+            && old_mode != new_mode
+            && delta.old_file().id() == worktree_id(repo, &delta.new_file());
+        let kind = match delta.status() {
+            git2::Delta::Added => ChangeKind::Added,
+            git2::Delta::Deleted => ChangeKind::Deleted,
+            git2::Delta::Untracked => ChangeKind::Untracked,
+            git2::Delta::Renamed => ChangeKind::Renamed,
+            git2::Delta::Copied => ChangeKind::Copied,
+            git2::Delta::Typechange => ChangeKind::TypeChange,
+            _ if mode_changed => ChangeKind::ModeChanged,
+            _ => ChangeKind::Modified,
+        };
+        if kind == ChangeKind::Untracked && ignored.iter().any(|i| i == path) {
+            continue;
+        }
+
+        // Binary files count as no lines, like `git diff --numstat`
+        let (mut added, mut removed, mut binary) = (0, 0, false);
+        if count_lines {
+            if let Some(patch) = git2::Patch::from_diff(&diff, idx)? {
+                let (_, a, r) = patch.line_stats()?;
+                added = a;
+                removed = r;
+                binary = patch.delta().flags().is_binary();
+            }
+        }
+
+        let (old_mode, new_mode) = if old_mode != new_mode
+            && old_mode != git2::FileMode::Unreadable
+            && new_mode != git2::FileMode::Unreadable
+        {
+            (
+                Some(format!("{:o}", u32::from(old_mode))),
+                Some(format!("{:o}", u32::from(new_mode))),
+            )
+        } else {
+            (None, None)
+        };
+
+        output.push(FileChange {
+            path: path.to_string(),
+            old_path,
+            kind,
+            added,
+            removed,
+            binary,
+            old_mode,
+            new_mode,
+        });
+    }
+
+    output.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(kind: ChangeKind, added: usize, removed: usize) -> FileChange {
+        FileChange {
+            path: String::from("README.md"),
+            old_path: None,
+            kind,
+            added,
+            removed,
+            binary: false,
+            old_mode: None,
+            new_mode: None,
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(change(ChangeKind::Modified, 16, 6).summary(), "[+16, -6]");
+        assert_eq!(change(ChangeKind::Modified, 0, 2).summary(), "[-2]");
+        assert_eq!(change(ChangeKind::Added, 3, 0).summary(), "[+3]");
+        assert_eq!(change(ChangeKind::Untracked, 0, 0).summary(), "[new]");
+        assert_eq!(change(ChangeKind::Deleted, 0, 5).summary(), "[deleted]");
+        assert_eq!(change(ChangeKind::Renamed, 0, 0).summary(), "[renamed]");
+        assert_eq!(
+            change(ChangeKind::Renamed, 1, 1).summary(),
+            "[renamed, +1, -1]"
+        );
+    }
+
+    #[test]
+    fn test_sync_summary() {
+        let mut state = SyncState {
+            main: String::from("main"),
+            ahead_main: 2,
+            behind_main: 1,
+            upstream: None,
+            needs_sync: true,
+            needs_upload: true,
+        };
+        assert_eq!(
+            state.summary(),
+            "2 ahead, 1 behind main | not uploaded | needs sync, needs upload"
+        );
+
+        state.upstream = Some(Upstream {
+            name: String::from("origin/feat"),
+            ahead: 0,
+            behind: 0,
+        });
+        state.needs_sync = false;
+        state.needs_upload = false;
+        assert_eq!(
+            state.summary(),
+            "2 ahead, 1 behind main | 0 ahead, 0 behind origin/feat | up to date"
+        );
+    }
+
+    #[test]
+    fn test_binary_and_mode_summary() {
+        let mut binary = change(ChangeKind::Modified, 0, 0);
+        binary.binary = true;
+        assert_eq!(binary.summary(), "[binary]");
+
+        let mut mode = change(ChangeKind::ModeChanged, 0, 0);
+        mode.old_mode = Some(String::from("100644"));
+        mode.new_mode = Some(String::from("100755"));
+        assert_eq!(mode.summary(), "[mode 644 -> 755]");
+    }
+}
@@ -14,7 +14,28 @@ pub fn select<S: ToString + std::fmt::Display + ?Sized>(
 
     Ok(dialoguer::Select::new()
         .default(0)
-        .items(&options)
+        .items(options)
         .interact()
         .unwrap())
 }
+
+// Lets the user pick any number of options, all of them selected to start with
+pub fn multi_select(prompt: &str, options: &[String]) -> Vec<usize> {
+    println!("{}", prompt);
+
+    let defaults = vec![true; options.len()];
+    dialoguer::MultiSelect::new()
+        .items(options)
+        .defaults(&defaults)
+        .interact()
+        .unwrap()
+}
+
+// Asks a yes/no question, defaulting to no
+pub fn confirm(prompt: &str) -> bool {
+    dialoguer::Confirm::new()
+        .with_prompt(prompt)
+        .default(false)
+        .interact()
+        .unwrap()
+}
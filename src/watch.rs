@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+// Directories which hold build output, dependencies or git's own data rather than source, so
+// they're skipped even if they aren't in .gitignore
+const IGNORED_DIRS: &[&str] = &[
+    ".git",
+    "target",
+    "node_modules",
+    "build",
+    "dist",
+    "out",
+    "__pycache__",
+    ".cache",
+];
+
+fn is_ignored_dir(name: &str) -> bool {
+    IGNORED_DIRS.contains(&name)
+}
+
+// Editors write swap, backup and lock files alongside the files being edited, which change
+// constantly and shouldn't count as edits
+fn is_temporary_file(name: &str) -> bool {
+    name.ends_with(".swp")
+        || name.ends_with(".swo")
+        || name.ends_with(".swx")
+        || name.ends_with('~')
+        || name.ends_with(".tmp")
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+        || name.starts_with(".goutputstream-")
+        // vim checks whether it can write to a directory by creating this file
+        || name == "4913"
+}
+
+struct Root {
+    path: PathBuf,
+    repo: git2::Repository,
+}
+
+impl Root {
+    fn is_ignored(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.path) {
+            Ok(r) => r,
+            Err(_) => return true,
+        };
+        if relative.as_os_str().is_empty() {
+            return false;
+        }
+        self.repo.is_path_ignored(relative).unwrap_or(false)
+    }
+}
+
+// Watches a set of worktrees for edits. inotify watches aren't recursive, so every directory
+// gets its own watch, and new directories are added as they're created.
+pub struct Watcher {
+    inotify: Inotify,
+    roots: Vec<Root>,
+    dirs: HashMap<WatchDescriptor, (usize, PathBuf)>,
+}
+
+impl Watcher {
+    pub fn new(workdirs: &[String]) -> Result<Self, String> {
+        let inotify = Inotify::init().map_err(|e| format!("couldn't start inotify: {}", e))?;
+        let mut watcher = Watcher {
+            inotify,
+            roots: Vec::new(),
+            dirs: HashMap::new(),
+        };
+
+        for workdir in workdirs {
+            let repo = git2::Repository::open(workdir)
+                .map_err(|e| format!("couldn't open {}: {}", workdir, e.message()))?;
+            watcher.roots.push(Root {
+                path: PathBuf::from(workdir),
+                repo,
+            });
+            let idx = watcher.roots.len() - 1;
+            watcher.watch_dir(idx, PathBuf::from(workdir))?;
+        }
+
+        Ok(watcher)
+    }
+
+    fn watch_dir(&mut self, root: usize, dir: PathBuf) -> Result<(), String> {
+        let mask = WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR;
+        let wd = match self.inotify.add_watch(&dir, mask) {
+            Ok(wd) => wd,
+            // The directory may have been removed again already
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("couldn't watch {}: {}", dir.display(), e)),
+        };
+        self.dirs.insert(wd, (root, dir.clone()));
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => return Ok(()),
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let path = entry.path();
+            if is_dir && !is_ignored_dir(&name) && !self.roots[root].is_ignored(&path) {
+                self.watch_dir(root, path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the events that are currently queued, returning the roots with relevant changes
+    fn read_events(&mut self) -> Result<Vec<usize>, String> {
+        let mut buffer = [0; 4096];
+        let mut changed = Vec::new();
+        let mut new_dirs = Vec::new();
+
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(format!("couldn't read events: {}", e)),
+            };
+
+            let mut any = false;
+            for event in events {
+                any = true;
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+
+                let (root, dir) = match self.dirs.get(&event.wd) {
+                    Some(d) => d.clone(),
+                    None => continue,
+                };
+                let name = match event.name {
+                    Some(n) => n.to_string_lossy().to_string(),
+                    None => continue,
+                };
+                let path = dir.join(&name);
+
+                if event.mask.contains(EventMask::ISDIR) {
+                    if is_ignored_dir(&name) || self.roots[root].is_ignored(&path) {
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::CREATE)
+                        || event.mask.contains(EventMask::MOVED_TO)
+                    {
+                        new_dirs.push((root, path));
+                    }
+                } else if is_temporary_file(&name) || self.roots[root].is_ignored(&path) {
+                    continue;
+                }
+
+                if !changed.contains(&root) {
+                    changed.push(root);
+                }
+            }
+
+            if !any {
+                break;
+            }
+        }
+
+        for (root, dir) in new_dirs {
+            self.watch_dir(root, dir)?;
+        }
+
+        Ok(changed)
+    }
+
+    // Sleeps until events are queued, or until `timeout` passes if there is one
+    fn wait_for_events(&self, timeout: Option<Duration>) -> Result<(), String> {
+        let mut fd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = match timeout {
+            Some(t) => t.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        if unsafe { libc::poll(&mut fd, 1, timeout) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(format!("couldn't wait for events: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    // Blocks until something changes, then until nothing has changed for `quiet_period`.
+    // Returns the workdirs which were edited.
+    pub fn wait(&mut self, quiet_period: Duration) -> Result<Vec<String>, String> {
+        let mut changed: Vec<usize> = Vec::new();
+        let mut last_change = Instant::now();
+
+        loop {
+            let timeout = if changed.is_empty() {
+                None
+            } else {
+                match quiet_period.checked_sub(last_change.elapsed()) {
+                    Some(remaining) if !remaining.is_zero() => Some(remaining),
+                    _ => break,
+                }
+            };
+            self.wait_for_events(timeout)?;
+
+            let events = self.read_events()?;
+            if !events.is_empty() {
+                last_change = Instant::now();
+                for root in events {
+                    if !changed.contains(&root) {
+                        changed.push(root);
+                    }
+                }
+            }
+        }
+
+        changed.sort_unstable();
+        Ok(changed
+            .into_iter()
+            .map(|idx| self.roots[idx].path.to_string_lossy().to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_temporary_file() {
+        assert!(is_temporary_file(".main.rs.swp"));
+        assert!(is_temporary_file("main.rs~"));
+        assert!(is_temporary_file(".#main.rs"));
+        assert!(is_temporary_file("#main.rs#"));
+        assert!(is_temporary_file("4913"));
+        assert!(!is_temporary_file("main.rs"));
+        assert!(!is_temporary_file("#"));
+        assert!(!is_temporary_file("swp.rs"));
+    }
+}
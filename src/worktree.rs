@@ -0,0 +1,49 @@
+// Builds a tree of everything in the worktree, including untracked files, the same way
+// `git add .` would. The repository's index file isn't modified.
+pub fn tree<'a>(repo: &'a git2::Repository) -> Result<git2::Tree<'a>, git2::Error> {
+    tree_excluding(repo, &[])
+}
+
+// Like `tree`, but leaves out the given untracked files, relative to the root of the worktree
+pub fn tree_excluding<'a>(
+    repo: &'a git2::Repository,
+    excluded: &[String],
+) -> Result<git2::Tree<'a>, git2::Error> {
+    let mut index = repo.index()?;
+    let mut skip = |path: &std::path::Path, _: &[u8]| -> i32 {
+        let skipped = path
+            .to_str()
+            .map(|p| excluded.iter().any(|e| e == p))
+            .unwrap_or(false);
+        if skipped {
+            1
+        } else {
+            0
+        }
+    };
+    index.add_all(
+        ["*"].iter(),
+        git2::IndexAddOption::DEFAULT,
+        Some(&mut skip as &mut git2::IndexMatchedPath),
+    )?;
+    index.update_all(["*"].iter(), None)?;
+    let oid = index.write_tree()?;
+    repo.find_tree(oid)
+}
+
+// Records the state of the worktree as a commit on top of HEAD, without moving any branch
+pub fn commit(repo: &git2::Repository, message: &str) -> Result<git2::Oid, git2::Error> {
+    commit_tree(repo, &tree(repo)?, message)
+}
+
+pub fn commit_tree(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    message: &str,
+) -> Result<git2::Oid, git2::Error> {
+    let head = repo.head()?.peel_to_commit()?;
+    let sig = repo
+        .signature()
+        .or_else(|_| git2::Signature::now("g2", "g2@localhost"))?;
+    repo.commit(None, &sig, &sig, message, tree, &[&head])
+}